@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use mkey_exporter::config::{Rule, RuleGroup, RulePattern};
+use mkey_exporter::config::{Action, Rule, RuleGroup, RulePattern};
 use mkey_exporter::keys::LabelParser;
 use mtop_client::Meta;
 
@@ -34,15 +34,18 @@ fn new_config() -> RuleGroup {
         rules: vec![
             Rule {
                 pattern: RulePattern::new(r"^\w+:([\w\-]+):").unwrap(),
+                action: Action::Label,
                 label_name: "user".to_owned(),
                 label_value: "$1".to_owned(),
             },
             Rule {
                 pattern: RulePattern::new(r"^(\w+):").unwrap(),
+                action: Action::Label,
                 label_name: "type".to_owned(),
                 label_value: "$1".to_owned(),
             },
         ],
+        ..Default::default()
     }
 }
 
@@ -1,31 +1,44 @@
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::Router;
-use clap::{Parser, ValueHint};
-use mkey_exporter::http::RequestState;
+use clap::{Args, Parser, Subcommand, ValueEnum, ValueHint};
+use mkey_exporter::backoff::{self, Backoff};
+use mkey_exporter::config::RuleGroup;
+use mkey_exporter::crawler::CrawlerPool;
+use mkey_exporter::http::{RateLimit, RequestState};
 use mkey_exporter::keys::LabelParser;
-use mkey_exporter::metrics::Metrics;
-use mtop_client::{MemcachedPool, MtopError, PoolConfig, TLSConfig};
-use prometheus_client::registry::Registry;
-use std::collections::{HashMap, HashSet};
+use mkey_exporter::metrics::{Cycle, Metrics};
+use mkey_exporter::pipeline::{WorkerPool, Workers};
+use mkey_exporter::report::Report;
+use mkey_exporter::source::KeySource;
+use mkey_exporter::updater::{self, Updater};
+use mtop_client::{Meta, TLSConfig};
 use std::error::Error;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{io, process};
-use tokio::runtime::Handle;
-use tokio::time::Instant;
+use std::time::{Duration, SystemTime};
+use std::{fs, io, process};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tower_http::trace::TraceLayer;
 use tracing::Level;
 
 const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 9761);
 const DEFAULT_REFRESH_SECS: u64 = 180;
+const DEFAULT_TIMEOUT_SECS: u64 = 150;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF_SECS: u64 = 1;
+const DEFAULT_STARTUP_JITTER_SECS: u64 = 10;
+const DEFAULT_REFRESH_LIMIT_SECS: u64 = 30;
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_RELOAD_SECS: u64 = 30;
 const DEFAULT_LOG_LEVEL: Level = Level::INFO;
 const DEFAULT_HOST: &str = "localhost:11211";
 
 /// Export metadata about memcached entries based on rules applied to their keys.
 #[derive(Debug, Parser)]
-#[clap(name = "mkey_exporter", version = clap::crate_version!())]
+#[clap(name = "mkey_exporter", version = clap::crate_version!(), subcommand_negates_reqs = true)]
 struct MkeyExporterApplication {
     /// Logging verbosity. Allowed values are 'trace', 'debug', 'info', 'warn', and 'error'
     /// (case insensitive)
@@ -38,14 +51,80 @@ struct MkeyExporterApplication {
     #[arg(long, default_value_t = DEFAULT_BIND_ADDR.into())]
     bind: SocketAddr,
 
-    /// Memcached host to connect to in the form 'hostname:port'.
-    #[arg(long, default_value_t = DEFAULT_HOST.to_owned(), value_hint = ValueHint::Hostname)]
-    host: String,
+    #[command(flatten)]
+    connection: ConnectionArgs,
 
-    /// Fetch cache keys from the Memcached server at this interval, in seconds
+    #[command(flatten)]
+    workers: WorkerArgs,
+
+    /// Fetch cache keys from the Memcached server this many seconds after the previous
+    /// update for the server finished
     #[arg(long, default_value_t = DEFAULT_REFRESH_SECS)]
     refresh_secs: u64,
 
+    /// Fetch cache keys only when metrics are scraped and the last update for a server
+    /// finished more than this many seconds ago, instead of at a fixed interval. Scrapes
+    /// that arrive while an update is running wait for it instead of starting another.
+    #[arg(long)]
+    max_age_secs: Option<u64>,
+
+    /// Give up fetching cache keys from a Memcached server after this many seconds and
+    /// count the update as a failure. Set to 0 to wait for updates indefinitely.
+    #[arg(long, default_value_t = DEFAULT_TIMEOUT_SECS)]
+    timeout_secs: u64,
+
+    /// Retry fetching cache keys from a Memcached server this many times within a single
+    /// update when it fails, before counting the update as a failure.
+    #[arg(long, default_value_t = DEFAULT_RETRIES)]
+    retries: u32,
+
+    /// Wait this many seconds before the first retry of a failed update. The wait doubles
+    /// for each retry after that, up to 30 seconds, and is randomized to avoid retrying
+    /// many servers at the same time.
+    #[arg(long, default_value_t = DEFAULT_RETRY_BACKOFF_SECS)]
+    retry_backoff_secs: u64,
+
+    /// Wait a random amount of time up to this many seconds before the first update for
+    /// each server, so that many exporters started together don't fetch keys from every
+    /// server at the same time. Set to 0 to start updates immediately.
+    #[arg(long, default_value_t = DEFAULT_STARTUP_JITTER_SECS)]
+    startup_jitter_secs: u64,
+
+    /// Allow updates to be triggered by 'POST /-/refresh' at most once per this many
+    /// seconds, so that the endpoint can't be used to constantly fetch keys from Memcached.
+    #[arg(long, default_value_t = DEFAULT_REFRESH_LIMIT_SECS)]
+    refresh_limit_secs: u64,
+
+    /// Check the configuration file for changes at this interval, in seconds, and reload
+    /// rules when it has been modified. Rules are always reloaded on SIGHUP. Set to 0 to
+    /// disable checking the configuration file for changes.
+    #[arg(long, default_value_t = DEFAULT_RELOAD_SECS)]
+    reload_secs: u64,
+
+    /// Path to configuration file providing key parsing rules.
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Options for connecting to Memcached servers, shared by the server and subcommands
+/// that fetch keys.
+#[derive(Debug, Args)]
+struct ConnectionArgs {
+    /// Memcached host to connect to in the form 'hostname:port'. May be given multiple
+    /// times to fetch keys from several servers concurrently. If not set, servers from
+    /// the configuration file are used, falling back to 'localhost:11211'.
+    #[arg(long, value_hint = ValueHint::Hostname)]
+    host: Vec<String>,
+
+    /// Where to fetch keys from: 'memcached' to crawl live servers, or 'file:PATH' to read
+    /// keys from a snapshot created by the 'record' subcommand. When reading a snapshot,
+    /// all servers in the file are used unless '--host' is given.
+    #[arg(long, default_value = "memcached")]
+    source: SourceArg,
+
     /// Enable TLS connections to the Memcached server.
     #[arg(long)]
     tls_enabled: bool,
@@ -65,16 +144,149 @@ struct MkeyExporterApplication {
     #[arg(long, requires = "tls_key", value_hint = ValueHint::FilePath)]
     tls_cert: Option<PathBuf>,
 
-    /// Optional client key to use to authenticate with the Memcached server. Note that this may
-    /// or may not be required based on how the Memcached server is configured.
+    /// Optional client key to use to authenticate with the Memcached server, in PKCS#8, RSA, or
+    /// EC PEM format. Note that this may or may not be required based on how the Memcached
+    /// server is configured.
     #[arg(long, requires = "tls_cert", value_hint = ValueHint::FilePath)]
     tls_key: Option<PathBuf>,
+}
+
+/// Options for how many threads are used to extract labels from keys, shared by the server
+/// and subcommands that fetch keys.
+#[derive(Debug, Args)]
+struct WorkerArgs {
+    /// Number of threads used to extract labels from keys and aggregate them, shared by
+    /// all servers. When 1 with a single shard, keys are processed on the same task that
+    /// fetches them.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: u64,
+
+    /// Number of shards keys are divided into, based on a hash of each key, when extracting
+    /// labels. Each shard has its own partial counts that are merged once all keys have been
+    /// processed. Defaults to the number of threads.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    shards: Option<u64>,
+}
+
+impl WorkerArgs {
+    fn workers(&self) -> Workers {
+        Workers {
+            threads: self.threads as usize,
+            shards: self.shards.unwrap_or(self.threads) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SourceArg {
+    Memcached,
+    File(PathBuf),
+}
+
+impl FromStr for SourceArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+            _ if s == "memcached" => Ok(Self::Memcached),
+            _ => Err(format!("expected 'memcached' or 'file:PATH', got '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    Check(CheckCommand),
+    Test(TestCommand),
+    Explain(ExplainCommand),
+    Scan(ScanCommand),
+    Record(RecordCommand),
+}
 
+/// Validate a rule configuration file without connecting to Memcached.
+///
+/// Exits with a non-zero status if the file cannot be parsed, or any rule has an invalid
+/// label name or references capture groups that don't exist in its pattern.
+#[derive(Debug, Args)]
+struct CheckCommand {
     /// Path to configuration file providing key parsing rules.
     #[arg(required = true, value_hint = ValueHint::FilePath)]
     config: PathBuf,
 }
 
+/// Run the tests in the 'tests' section of a rule configuration file.
+///
+/// Each test is a key and the exact set of labels rules are expected to produce for it.
+/// Exits with a non-zero status if the file is invalid or any test fails.
+#[derive(Debug, Args)]
+struct TestCommand {
+    /// Path to configuration file providing key parsing rules and tests.
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    config: PathBuf,
+}
+
+/// Show how each rule in a configuration file applies to one or more keys.
+///
+/// For each rule, prints whether it matched, the capture groups found, the expanded label
+/// value, and if it was skipped because an earlier rule already set the same label.
+#[derive(Debug, Args)]
+struct ExplainCommand {
+    /// Path to configuration file providing key parsing rules.
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    config: PathBuf,
+
+    /// Memcached keys to explain. If not given, keys are read from stdin, one per line.
+    keys: Vec<String>,
+}
+
+/// Fetch keys from Memcached once and print a report of label sets without starting a server.
+///
+/// Keys are processed using the same rules, drops, and limits as the server. Label sets are
+/// printed sorted by total size, largest first, and the process exits after a single crawl.
+#[derive(Debug, Args)]
+struct ScanCommand {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    workers: WorkerArgs,
+
+    /// Output format for the report.
+    #[arg(long, value_enum, default_value_t = ScanFormat::Table)]
+    format: ScanFormat,
+
+    /// Path to configuration file providing key parsing rules.
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    config: PathBuf,
+}
+
+/// Fetch keys from Memcached once and save them to a compressed snapshot file.
+///
+/// Snapshots can be used in place of live servers with '--source file:PATH' to try rules
+/// against a realistic set of keys without connecting to Memcached.
+#[derive(Debug, Args)]
+struct RecordCommand {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Optional configuration file to read the list of servers from when '--host' is not given.
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    config: Option<PathBuf>,
+
+    /// Path to write the zstd compressed snapshot to. Replaced if it already exists.
+    #[arg(required = true, value_hint = ValueHint::FilePath)]
+    output: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ScanFormat {
+    Table,
+    Json,
+    Csv,
+    Openmetrics,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let opts = MkeyExporterApplication::parse();
@@ -85,114 +297,84 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     )
     .expect("failed to set tracing subscriber");
 
-    let cfg = mkey_exporter::config::from_path(&opts.config).unwrap_or_else(|e| {
-        tracing::error!(message = "unable to parse rule configuration", path = ?opts.config, err = %e);
-        process::exit(1);
-    });
+    match opts.command {
+        Some(Command::Check(cmd)) => process::exit(run_check(&cmd)),
+        Some(Command::Test(cmd)) => process::exit(run_test(&cmd)),
+        Some(Command::Explain(cmd)) => process::exit(run_explain(&cmd)),
+        Some(Command::Scan(cmd)) => process::exit(run_scan(&cmd).await),
+        Some(Command::Record(cmd)) => process::exit(run_record(&cmd).await),
+        None => run_server(opts).await,
+    }
+}
 
-    let pool = MemcachedPool::new(
-        Handle::current(),
-        PoolConfig {
-            tls: TLSConfig {
-                enabled: opts.tls_enabled,
-                ca_path: opts.tls_ca,
-                cert_path: opts.tls_cert,
-                key_path: opts.tls_key,
-                server_name: opts.tls_server_name,
-            },
-            ..Default::default()
-        },
-    )
-    .await
-    .unwrap_or_else(|e| {
-        tracing::error!(message = "unable to initialize memcached client", host = %opts.host, err = %e);
+async fn run_server(opts: MkeyExporterApplication) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Required unless a subcommand is used, enforced during argument parsing.
+    let config_path = opts.config.clone().expect("config path is required");
+    let cfg = mkey_exporter::config::from_path(&config_path).unwrap_or_else(|e| {
+        tracing::error!(message = "unable to parse rule configuration", path = ?config_path, err = %e);
         process::exit(1);
     });
 
-    connect(&opts.host, &pool).await.unwrap_or_else(|e| {
-        tracing::error!(message = "unable to connect to memcached host", host = %opts.host, err = %e);
-        process::exit(1);
-    });
+    // Servers that can't be reached yet are retried by update loops in the background
+    // instead of preventing the exporter from starting.
+    let (source, hosts) = new_source(&opts.connection, &cfg).await;
 
-    let mut registry = Registry::default();
-    let metrics = Metrics::new(&mut registry);
+    let metrics = Arc::new(Metrics::new());
     let profiler = mkey_exporter::profile::build().unwrap_or_else(|e| {
         tracing::error!(message = "unable to initialize CPU profiler", err = %e);
         process::exit(1);
     });
 
-    tokio::spawn(async move {
-        let parser = LabelParser::new(&cfg);
-        let mut interval = tokio::time::interval(Duration::from_secs(opts.refresh_secs));
-        let mut to_remove = HashSet::new();
-
-        loop {
-            let start = interval.tick().await;
-
-            let mut client = match pool.get(&opts.host).await {
-                Ok(c) => c,
-                Err(e) => {
-                    tracing::warn!(message = "failed to connect to server", host = %opts.host, err = %e);
-                    metrics.incr_failure();
-                    continue;
-                }
-            };
-
-            let metas = match client.metas().await {
-                Ok(m) => m,
-                Err(e) => {
-                    tracing::warn!(message = "failed to fetch key metas", host = %opts.host, err = %e);
-                    metrics.incr_failure();
-                    continue;
-                }
-            };
-
-            let mut counts_by_labels = HashMap::new();
-            let num_keys = metas.len();
-
-            for m in metas.iter() {
-                let labels = parser.extract(m);
-                to_remove.remove(&labels);
-
-                let e = counts_by_labels.entry(labels).or_insert_with(LabelCounts::default);
-
-                e.count += 1;
-                e.size += m.size as i64;
-            }
-
-            // At the end of every update loop, we add all the unique label sets we found to
-            // the "to remove" set. At the beginning of the next update loop, we remove any of
-            // the labels that are generated from the new meta objects from the "to remove" set.
-            // At this point in the loop we are left with a set of labels that existed the last
-            // iteration but no longer do and are thus safe to remove from our metric registry.
-            metrics.cleanup_keys(&to_remove);
-            to_remove.clear();
-
-            let num_unique_labels = counts_by_labels.len();
-            for (labels, c) in counts_by_labels {
-                metrics.update_key(&labels, c.count, c.size);
-                to_remove.insert(labels);
-            }
-
-            // Try to reduce memory usage down from the high-water mark.
-            to_remove.shrink_to_fit();
-
-            let time_taken = Instant::now().duration_since(start);
-            tracing::info!(
-                message = "updated metrics for memcached keys",
-                rule_group = cfg.name,
-                num_keys = num_keys,
-                num_unique_labels = num_unique_labels,
-                time_taken = ?time_taken,
-            );
-            metrics.incr_success(time_taken);
-            pool.put(client).await;
+    metrics.reload_success();
+    metrics.set_limits(cfg.limits.clone());
+    let (rules_tx, rules_rx) = watch::channel(Arc::new(cfg));
+    let source = Arc::new(source);
+    let refresh = Duration::from_secs(opts.refresh_secs);
+    let workers = opts.workers.workers();
+
+    let mut updater = Updater::new(&hosts, rules_rx, source, metrics.clone(), workers).with_backoff(Backoff {
+        retries: opts.retries,
+        initial: Duration::from_secs(opts.retry_backoff_secs),
+        max: MAX_RETRY_BACKOFF,
+    });
+    if opts.timeout_secs > 0 {
+        updater = updater.with_timeout(Duration::from_secs(opts.timeout_secs));
+    }
+
+    let updater = Arc::new(updater);
+    let max_age = opts.max_age_secs.map(Duration::from_secs);
+
+    // When updating on demand, updates are only run by scrapes.
+    if max_age.is_none() {
+        for host in hosts {
+            let delay = backoff::jitter(Duration::from_secs(opts.startup_jitter_secs));
+            tokio::spawn(update_loop(host, updater.clone(), refresh, delay));
         }
+    }
+
+    tokio::spawn(reload_loop(
+        config_path,
+        rules_tx,
+        metrics.clone(),
+        Duration::from_secs(opts.reload_secs),
+    ));
+
+    let state = Arc::new(RequestState {
+        metrics: metrics.clone(),
+        updater,
+        max_age,
+        refresh_limit: RateLimit::new(Duration::from_secs(opts.refresh_limit_secs)),
+        profiler,
     });
-
-    let state = Arc::new(RequestState { registry, profiler });
     let app = Router::new()
         .route("/metrics", get(mkey_exporter::http::text_metrics_handler))
+        .route("/-/healthy", get(mkey_exporter::http::healthy_handler))
+        .route("/-/ready", get(mkey_exporter::http::ready_handler))
+        .route("/status", get(mkey_exporter::http::status_handler))
+        .route("/-/refresh", post(mkey_exporter::http::refresh_handler))
+        .route("/api/v1/summary", get(mkey_exporter::http::summary_handler))
+        .route("/debug/keys", get(mkey_exporter::http::keys_handler))
+        .route("/debug/unmatched", get(mkey_exporter::http::unmatched_handler))
         .route("/debug/pprof/profile", get(mkey_exporter::http::pprof_handler))
         .layer(TraceLayer::new_for_http())
         .with_state(state.clone());
@@ -219,16 +401,406 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     Ok(())
 }
 
-#[derive(Debug, Default)]
-struct LabelCounts {
-    count: i64,
-    size: i64,
+/// Parse and validate the configuration file, printing any problems found. Returns the
+/// exit code for the process.
+fn run_check(cmd: &CheckCommand) -> i32 {
+    let cfg = match mkey_exporter::config::parse_path(&cmd.config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}: unable to parse rule configuration: {}", cmd.config.display(), e);
+            return 1;
+        }
+    };
+
+    match cfg.validate() {
+        Ok(_) => {
+            println!(
+                "{}: OK, rule group {:?} with {} rules",
+                cmd.config.display(),
+                cfg.name,
+                cfg.rules.len()
+            );
+            0
+        }
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("{}: {}", cmd.config.display(), e);
+            }
+            eprintln!("{}: {} problems found", cmd.config.display(), errors.len());
+            1
+        }
+    }
 }
 
-async fn connect(host: &str, pool: &MemcachedPool) -> Result<(), MtopError> {
-    let client = pool.get(host).await?;
-    pool.put(client).await;
-    Ok(())
+/// Run all tests from the configuration file, printing a diff for each failure. Returns
+/// the exit code for the process.
+fn run_test(cmd: &TestCommand) -> i32 {
+    let cfg = match mkey_exporter::config::from_path(&cmd.config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}: invalid rule configuration: {}", cmd.config.display(), e);
+            return 1;
+        }
+    };
+
+    let results = mkey_exporter::testing::run(&cfg);
+    for f in results.failures.iter() {
+        eprint!("{}: FAIL {}", cmd.config.display(), f);
+    }
+
+    println!(
+        "{}: {} passed, {} failed",
+        cmd.config.display(),
+        results.passed,
+        results.failures.len()
+    );
+
+    if results.is_success() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Print how rules from the configuration file apply to each key given as an argument or
+/// read from stdin. Returns the exit code for the process.
+fn run_explain(cmd: &ExplainCommand) -> i32 {
+    let cfg = match mkey_exporter::config::from_path(&cmd.config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}: invalid rule configuration: {}", cmd.config.display(), e);
+            return 1;
+        }
+    };
+
+    let parser = LabelParser::new(&cfg);
+    let explain = |key: &str| {
+        println!("key {:?}", key);
+        for e in parser.explain(key) {
+            println!("  {}", e);
+        }
+
+        let meta = Meta {
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        match parser.extract(&meta) {
+            Some(labels) => {
+                let labels = labels
+                    .iter()
+                    .map(|(name, value)| format!("{}={:?}", name, value))
+                    .collect::<Vec<_>>();
+                println!("  labels: {{{}}}", labels.join(", "));
+            }
+            None => println!("  labels: <dropped>"),
+        }
+    };
+
+    if !cmd.keys.is_empty() {
+        cmd.keys.iter().for_each(|k| explain(k));
+        return 0;
+    }
+
+    for line in io::stdin().lines() {
+        match line {
+            Ok(k) => explain(&k),
+            Err(e) => {
+                eprintln!("unable to read keys from stdin: {}", e);
+                return 1;
+            }
+        }
+    }
+
+    0
+}
+
+/// Fetch keys from each Memcached server once and print a report of label sets in the
+/// requested format. Returns the exit code for the process.
+async fn run_scan(cmd: &ScanCommand) -> i32 {
+    let cfg = match mkey_exporter::config::from_path(&cmd.config) {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            eprintln!("{}: invalid rule configuration: {}", cmd.config.display(), e);
+            return 1;
+        }
+    };
+
+    let (source, hosts) = new_source(&cmd.connection, &cfg).await;
+    let source = Arc::new(source);
+    // Every server is crawled at the same time using the same threads.
+    let pool = WorkerPool::new(cmd.workers.workers());
+    let mut tasks = JoinSet::new();
+    for host in hosts {
+        let (cfg, source, pool) = (cfg.clone(), source.clone(), pool.clone());
+        tasks.spawn(async move {
+            let res = updater::crawl(&host, &source, &cfg, &pool).await;
+            (host, res)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        match res.expect("scan task panicked") {
+            (host, Ok(a)) => results.push((host, a)),
+            (host, Err(e)) => {
+                eprintln!("{}: unable to fetch key metas: {}", host, e);
+                return 1;
+            }
+        }
+    }
+
+    for (host, a) in results.iter().filter(|(_, a)| a.sample_rate < 1.0) {
+        eprintln!(
+            "{}: counts and sizes are estimates based on a sample rate of {}",
+            host, a.sample_rate
+        );
+    }
+
+    let report = || Report::new(results.iter().map(|(h, a)| (h.as_str(), a)));
+    let out = match cmd.format {
+        ScanFormat::Table => report().table(),
+        ScanFormat::Csv => report().csv(),
+        ScanFormat::Json => match report().json() {
+            Ok(s) => s + "\n",
+            Err(e) => {
+                eprintln!("unable to encode report as JSON: {}", e);
+                return 1;
+            }
+        },
+        ScanFormat::Openmetrics => {
+            let metrics = Metrics::new();
+            metrics.set_limits(cfg.limits.clone());
+            let completed = SystemTime::now();
+            for (host, aggregation) in results {
+                // Durations are only shown on the status page of the server, not in metrics.
+                let duration = Duration::ZERO;
+                metrics.publish(
+                    &host,
+                    Cycle {
+                        aggregation,
+                        completed,
+                        duration,
+                    },
+                );
+            }
+
+            let mut buf = String::new();
+            if let Err(e) = metrics.encode_results(&mut buf) {
+                eprintln!("unable to encode report as OpenMetrics: {}", e);
+                return 1;
+            }
+            buf
+        }
+    };
+
+    print!("{}", out);
+    0
+}
+
+/// Fetch keys from each Memcached server once and write them to a compressed snapshot
+/// file. Returns the exit code for the process.
+async fn run_record(cmd: &RecordCommand) -> i32 {
+    let cfg = match &cmd.config {
+        Some(path) => match mkey_exporter::config::from_path(path) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}: invalid rule configuration: {}", path.display(), e);
+                return 1;
+            }
+        },
+        None => RuleGroup::default(),
+    };
+
+    let (source, hosts) = new_source(&cmd.connection, &cfg).await;
+    let mut out = match mkey_exporter::snapshot::create(&cmd.output) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}: unable to create snapshot: {}", cmd.output.display(), e);
+            return 1;
+        }
+    };
+
+    // Servers are fetched one at a time so that keys can be written to the snapshot as
+    // they are read without buffering them in memory.
+    let mut num_keys = 0;
+    for host in hosts.iter() {
+        let mut res = mkey_exporter::snapshot::write_server(&mut out, host);
+        let fetched = source
+            .metas(host, &mut |m: &Meta| {
+                if res.is_ok() {
+                    num_keys += 1;
+                    res = mkey_exporter::snapshot::write_meta(&mut out, m);
+                }
+            })
+            .await;
+
+        if let Err(e) = fetched {
+            eprintln!("{}: unable to fetch key metas: {}", host, e);
+            return 1;
+        }
+
+        if let Err(e) = res {
+            eprintln!("{}: unable to write snapshot: {}", cmd.output.display(), e);
+            return 1;
+        }
+    }
+
+    if let Err(e) = out.finish() {
+        eprintln!("{}: unable to write snapshot: {}", cmd.output.display(), e);
+        return 1;
+    }
+
+    println!(
+        "{}: recorded {} keys from {} servers",
+        cmd.output.display(),
+        num_keys,
+        hosts.len()
+    );
+    0
+}
+
+/// Get the Memcached servers to fetch keys from: hosts given on the command line take
+/// precedence over servers from the configuration file. Duplicates are removed.
+fn hosts(from_args: &[String], cfg: &RuleGroup) -> Vec<String> {
+    let candidates = if !from_args.is_empty() {
+        from_args
+    } else if !cfg.servers.is_empty() {
+        &cfg.servers
+    } else {
+        return vec![DEFAULT_HOST.to_owned()];
+    };
+
+    let mut out: Vec<String> = Vec::with_capacity(candidates.len());
+    for host in candidates {
+        if !out.contains(host) {
+            out.push(host.clone());
+        }
+    }
+
+    out
+}
+
+async fn update_loop(host: String, updater: Arc<Updater>, refresh: Duration, delay: Duration) {
+    tokio::time::sleep(delay).await;
+
+    // Until the first update succeeds, e.g. because the server isn't reachable yet when
+    // the exporter starts, keep trying with a backoff instead of waiting for the interval.
+    let backoff = updater.backoff();
+    let mut attempt = 0;
+    while !updater.update(&host).await {
+        let delay = backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        tracing::info!(message = "waiting for first successful update", host = %host, delay = ?delay);
+        tokio::time::sleep(delay).await;
+    }
+
+    updater.update_every(&host, refresh).await;
+}
+
+/// Create the source to fetch keys from along with the servers to fetch them for, or exit
+/// the process if the source cannot be initialized.
+async fn new_source(args: &ConnectionArgs, cfg: &RuleGroup) -> (KeySource, Vec<String>) {
+    match &args.source {
+        SourceArg::Memcached => {
+            let hosts = hosts(&args.host, cfg);
+            (KeySource::Memcached(new_pool(args, &hosts).await), hosts)
+        }
+        SourceArg::File(path) => {
+            let servers = mkey_exporter::snapshot::open(path).and_then(mkey_exporter::snapshot::servers);
+            let hosts = match servers {
+                Ok(_) if !args.host.is_empty() => hosts(&args.host, cfg),
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::error!(message = "unable to read snapshot", path = ?path, err = %e);
+                    process::exit(1);
+                }
+            };
+
+            (KeySource::Snapshot(path.clone()), hosts)
+        }
+    }
+}
+
+/// Create a connection pool for the Memcached servers or exit the process if it cannot be
+/// initialized.
+async fn new_pool(args: &ConnectionArgs, hosts: &[String]) -> CrawlerPool {
+    CrawlerPool::new(&TLSConfig {
+        enabled: args.tls_enabled,
+        ca_path: args.tls_ca.clone(),
+        cert_path: args.tls_cert.clone(),
+        key_path: args.tls_key.clone(),
+        server_name: args.tls_server_name.clone(),
+    })
+    .await
+    .unwrap_or_else(|e| {
+        tracing::error!(message = "unable to initialize memcached client", hosts = ?hosts, err = %e);
+        process::exit(1);
+    })
+}
+
+/// Reload rule configuration whenever SIGHUP is received or the modification time of the
+/// configuration file changes. Invalid configuration is logged and the previously loaded
+/// rules are kept.
+async fn reload_loop(path: PathBuf, rules: watch::Sender<Arc<RuleGroup>>, metrics: Arc<Metrics>, check: Duration) {
+    let mut hup = match sighup() {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(message = "unable to install SIGHUP handler", err = %e);
+            return;
+        }
+    };
+
+    let mut last_modified = modified(&path);
+
+    loop {
+        tokio::select! {
+            _ = hup.recv() => {
+                tracing::info!(message = "received SIGHUP, reloading rule configuration", path = ?path);
+            }
+            _ = modified_after(&path, last_modified, check) => {
+                tracing::info!(message = "configuration file changed, reloading rule configuration", path = ?path);
+            }
+        }
+
+        last_modified = modified(&path);
+        match mkey_exporter::config::from_path(&path) {
+            Ok(cfg) => {
+                tracing::info!(message = "reloaded rule configuration", path = ?path, rule_group = cfg.name, num_rules = cfg.rules.len());
+                // Replace the rules before clearing rule series so that updates still using
+                // the previous rules don't add to the new series.
+                metrics.set_limits(cfg.limits.clone());
+                rules.send_replace(Arc::new(cfg));
+                metrics.reload_success();
+            }
+            Err(e) => {
+                tracing::error!(message = "unable to reload rule configuration, keeping existing rules", path = ?path, err = %e);
+                metrics.reload_failure();
+            }
+        }
+    }
+}
+
+/// Resolve once the modification time of the file at `path` is different than `last`,
+/// checking every `check` interval. Never resolves if `check` is zero.
+async fn modified_after(path: &PathBuf, last: Option<SystemTime>, check: Duration) {
+    if check.is_zero() {
+        return std::future::pending().await;
+    }
+
+    let mut interval = tokio::time::interval(check);
+    // The first tick of an interval completes immediately, skip it.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if modified(path) != last {
+            return;
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
 async fn sigint() -> io::Result<()> {
@@ -248,3 +820,81 @@ async fn sigterm() -> io::Result<()> {
     // have both sigterm() and sigint() above to trigger shutdown of the server.
     std::future::pending::<io::Result<()>>().await
 }
+
+#[cfg(unix)]
+fn sighup() -> io::Result<Hangup> {
+    use tokio::signal::unix::{self, SignalKind};
+    Ok(Hangup(unix::signal(SignalKind::hangup())?))
+}
+
+#[cfg(not(unix))]
+fn sighup() -> io::Result<Hangup> {
+    Ok(Hangup)
+}
+
+/// Stream of SIGHUP signals used to trigger reloading configuration.
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    async fn recv(&mut self) -> Option<()> {
+        self.0.recv().await
+    }
+}
+
+/// No SIGHUP on windows. Reloading configuration is only triggered by the
+/// configuration file changing.
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending::<Option<()>>().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hosts, DEFAULT_HOST};
+    use mkey_exporter::config::RuleGroup;
+
+    fn new_rule_group(servers: &[&str]) -> RuleGroup {
+        RuleGroup {
+            servers: servers.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hosts_default() {
+        assert_eq!(vec![DEFAULT_HOST.to_owned()], hosts(&[], &new_rule_group(&[])));
+    }
+
+    #[test]
+    fn test_hosts_from_config() {
+        let cfg = new_rule_group(&["cache-a:11211", "cache-b:11211"]);
+        assert_eq!(vec!["cache-a:11211", "cache-b:11211"], hosts(&[], &cfg));
+    }
+
+    #[test]
+    fn test_hosts_args_take_precedence() {
+        let cfg = new_rule_group(&["cache-a:11211", "cache-b:11211"]);
+        let args = vec!["cache-c:11211".to_owned(), "cache-d:11211".to_owned()];
+        assert_eq!(vec!["cache-c:11211", "cache-d:11211"], hosts(&args, &cfg));
+    }
+
+    #[test]
+    fn test_hosts_duplicates_removed() {
+        let cfg = new_rule_group(&["cache-b:11211", "cache-a:11211", "cache-b:11211"]);
+        assert_eq!(vec!["cache-b:11211", "cache-a:11211"], hosts(&[], &cfg));
+
+        let args = vec![
+            "cache-a:11211".to_owned(),
+            "cache-a:11211".to_owned(),
+            "cache-c:11211".to_owned(),
+        ];
+        assert_eq!(vec!["cache-a:11211", "cache-c:11211"], hosts(&args, &cfg));
+    }
+}
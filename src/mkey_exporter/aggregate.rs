@@ -0,0 +1,525 @@
+use crate::config::{KeySamples, RankBy, RuleGroup};
+use crate::sample::random_u64;
+use mtop_client::Meta;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Replacement for parts of sampled keys that match a redaction pattern.
+pub const REDACTED: &str = "<redacted>";
+
+/// Aggregated counts and sizes of keys, indexed by the set of labels extracted from them.
+pub type Aggregates = HashMap<Vec<(String, String)>, LabelCounts>;
+
+/// Count and total size of all keys that produced the same set of labels, along with
+/// any optional distributions enabled in configuration.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LabelCounts {
+    pub count: i64,
+    pub size: i64,
+    pub ttl: Option<TtlCounts>,
+    pub item_sizes: Option<Distribution>,
+    pub keys: Option<KeySample>,
+}
+
+impl LabelCounts {
+    /// Add a single key to the totals.
+    pub fn add(&mut self, meta: &Meta) {
+        self.count += 1;
+        self.size += meta.size as i64;
+    }
+
+    /// Add all keys from another set of totals.
+    pub fn merge(&mut self, other: &LabelCounts) {
+        self.count += other.count;
+        self.size += other.size;
+
+        if let Some(o) = &other.ttl {
+            match &mut self.ttl {
+                Some(t) => t.merge(o),
+                None => self.ttl = Some(o.clone()),
+            }
+        }
+
+        if let Some(o) = &other.item_sizes {
+            match &mut self.item_sizes {
+                Some(d) => d.merge(o),
+                None => self.item_sizes = Some(o.clone()),
+            }
+        }
+
+        if let Some(o) = &other.keys {
+            match &mut self.keys {
+                Some(k) => k.merge(o),
+                None => self.keys = Some(o.clone()),
+            }
+        }
+    }
+
+    /// Multiply all counts and sizes by `factor`, rounding to the nearest whole number.
+    /// Used to estimate totals for all keys when only a sample of keys was processed.
+    pub fn scale(&mut self, factor: f64) {
+        self.count = scale(self.count, factor);
+        self.size = scale(self.size, factor);
+
+        if let Some(t) = &mut self.ttl {
+            t.scale(factor);
+        }
+
+        if let Some(d) = &mut self.item_sizes {
+            d.scale(factor);
+        }
+    }
+
+    /// Value used to decide which label sets are most important to keep when limiting
+    /// the number of series.
+    pub fn rank(&self, by: RankBy) -> i64 {
+        match by {
+            RankBy::Count => self.count,
+            RankBy::Size => self.size,
+        }
+    }
+}
+
+/// Expiration times of all keys that produced the same set of labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtlCounts {
+    /// Number of keys that never expire.
+    pub no_expiry: i64,
+    /// Number of keys past their expiration time that have not been reclaimed yet.
+    pub expired: i64,
+    /// Remaining time-to-live, in seconds, of keys that have not expired.
+    pub remaining: Distribution,
+}
+
+impl TtlCounts {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            no_expiry: 0,
+            expired: 0,
+            remaining: Distribution::new(bounds),
+        }
+    }
+
+    pub fn merge(&mut self, other: &TtlCounts) {
+        self.no_expiry += other.no_expiry;
+        self.expired += other.expired;
+        self.remaining.merge(&other.remaining);
+    }
+
+    pub fn scale(&mut self, factor: f64) {
+        self.no_expiry = scale(self.no_expiry, factor);
+        self.expired = scale(self.expired, factor);
+        self.remaining.scale(factor);
+    }
+}
+
+/// Distribution of observed values using the same layout as a Prometheus histogram: the
+/// count of values in each bucket (not cumulative) by upper bound, with `f64::MAX` as the
+/// upper bound of the last bucket to represent `+Inf`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Distribution {
+    pub sum: f64,
+    pub count: u64,
+    pub buckets: Vec<(f64, u64)>,
+}
+
+impl Distribution {
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut buckets: Vec<(f64, u64)> = bounds.iter().map(|b| (*b, 0)).collect();
+        buckets.push((f64::MAX, 0));
+
+        Self {
+            sum: 0.0,
+            count: 0,
+            buckets,
+        }
+    }
+
+    pub fn observe(&mut self, v: f64) {
+        self.sum += v;
+        self.count += 1;
+
+        if let Some((_, c)) = self.buckets.iter_mut().find(|(upper, _)| *upper >= v) {
+            *c += 1;
+        }
+    }
+
+    /// Add all values from another distribution. Both must have the same bucket bounds.
+    pub fn merge(&mut self, other: &Distribution) {
+        self.sum += other.sum;
+        self.count += other.count;
+
+        for ((_, c), (_, o)) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *c += *o;
+        }
+    }
+
+    /// Multiply the sum, count, and every bucket by `factor`. Bucket counts are scaled
+    /// cumulatively so that the total of all buckets always equals the scaled count.
+    pub fn scale(&mut self, factor: f64) {
+        self.sum *= factor;
+        self.count = (self.count as f64 * factor).round() as u64;
+
+        let (mut raw, mut scaled) = (0, 0);
+        for (_, c) in self.buckets.iter_mut() {
+            raw += *c;
+            let total = (raw as f64 * factor).round() as u64;
+            *c = total - scaled;
+            scaled = total;
+        }
+    }
+}
+
+/// Metadata of a single key kept as an example of the keys in a label set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SampledKey {
+    pub key: String,
+    pub size: u64,
+    pub expires: i64,
+}
+
+/// Uniform random sample of a fixed number of keys from a label set, kept using reservoir
+/// sampling so that memory use doesn't depend on the number of keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySample {
+    capacity: usize,
+    seen: u64,
+    rng: u64,
+    pub keys: Vec<SampledKey>,
+}
+
+impl KeySample {
+    pub fn new(capacity: usize) -> Self {
+        // Force the state to be non-zero since xorshift never leaves zero.
+        let seed = random_u64() | 1;
+        Self {
+            capacity,
+            seen: 0,
+            rng: seed,
+            keys: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Number of keys offered to the sample, including those not kept.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Offer a key to the sample. `redact` is only called for keys that are kept.
+    pub fn add<F>(&mut self, meta: &Meta, redact: F)
+    where
+        F: FnOnce(&str) -> String,
+    {
+        self.seen += 1;
+        let slot = if self.keys.len() < self.capacity {
+            Some(self.keys.len())
+        } else {
+            let i = (self.next() % self.seen) as usize;
+            (i < self.capacity).then_some(i)
+        };
+
+        if let Some(i) = slot {
+            let key = SampledKey {
+                key: redact(&meta.key),
+                size: meta.size,
+                expires: meta.expires,
+            };
+
+            if i < self.keys.len() {
+                self.keys[i] = key;
+            } else {
+                self.keys.push(key);
+            }
+        }
+    }
+
+    /// Combine with a sample of other keys from the same label set. Keys are picked from
+    /// each sample in proportion to the number of keys each has seen.
+    pub fn merge(&mut self, other: &KeySample) {
+        let mut ours = std::mem::take(&mut self.keys);
+        let mut theirs = other.keys.clone();
+        let (mut weight_ours, mut weight_theirs) = (self.seen, other.seen);
+
+        while self.keys.len() < self.capacity && !(ours.is_empty() && theirs.is_empty()) {
+            let pick_ours = if ours.is_empty() {
+                false
+            } else if theirs.is_empty() {
+                true
+            } else {
+                self.next() % (weight_ours + weight_theirs) < weight_ours
+            };
+
+            // Each kept key stands in for an equal share of the keys seen by its sample.
+            let (keys, weight) = if pick_ours {
+                (&mut ours, &mut weight_ours)
+            } else {
+                (&mut theirs, &mut weight_theirs)
+            };
+
+            *weight -= *weight / keys.len() as u64;
+            let i = (self.next() % keys.len() as u64) as usize;
+            self.keys.push(keys.swap_remove(i));
+        }
+
+        self.seen += other.seen;
+    }
+
+    fn next(&mut self) -> u64 {
+        // xorshift64, fast enough to call for every key.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+fn scale(v: i64, factor: f64) -> i64 {
+    (v as f64 * factor).round() as i64
+}
+
+/// Add keys to the totals for their label set, including any optional distributions
+/// enabled in configuration.
+#[derive(Debug)]
+pub struct Aggregator<'a> {
+    ttl_buckets: Option<&'a [f64]>,
+    size_buckets: Option<&'a [f64]>,
+    key_samples: Option<&'a KeySamples>,
+    now: i64,
+}
+
+impl<'a> Aggregator<'a> {
+    /// Create a new `Aggregator` using `now` to compute the remaining time-to-live of keys.
+    pub fn new(config: &'a RuleGroup, now: SystemTime) -> Self {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        Self {
+            ttl_buckets: config.ttl.as_ref().map(|t| t.buckets.as_slice()),
+            size_buckets: config.item_sizes.as_ref().map(|s| s.buckets.as_slice()),
+            key_samples: config.key_samples.as_ref(),
+            now,
+        }
+    }
+
+    pub fn add(&self, counts: &mut LabelCounts, meta: &Meta) {
+        counts.add(meta);
+
+        if let Some(bounds) = self.ttl_buckets {
+            let ttl = counts.ttl.get_or_insert_with(|| TtlCounts::new(bounds));
+            // Memcached uses -1 for keys that never expire.
+            if meta.expires <= 0 {
+                ttl.no_expiry += 1;
+            } else if meta.expires <= self.now {
+                ttl.expired += 1;
+            } else {
+                ttl.remaining.observe((meta.expires - self.now) as f64);
+            }
+        }
+
+        if let Some(bounds) = self.size_buckets {
+            counts
+                .item_sizes
+                .get_or_insert_with(|| Distribution::new(bounds))
+                .observe(meta.size as f64);
+        }
+
+        if let Some(samples) = self.key_samples {
+            counts
+                .keys
+                .get_or_insert_with(|| KeySample::new(samples.size))
+                .add(meta, |key| redact(key, samples));
+        }
+    }
+}
+
+fn redact(key: &str, samples: &KeySamples) -> String {
+    let mut out = key.to_owned();
+    for pattern in samples.redact.iter() {
+        if pattern.is_match(&out) {
+            out = pattern.replace_all(&out, REDACTED).into_owned();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aggregator, Distribution, KeySample, LabelCounts, TtlCounts, REDACTED};
+    use crate::config::{ItemSizes, KeySamples, RuleGroup, RulePattern, Ttl};
+    use mtop_client::Meta;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn new_meta(expires: i64, size: u64) -> Meta {
+        Meta {
+            key: "test".to_owned(),
+            expires,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_distribution_observe() {
+        let mut d = Distribution::new(&[1.0, 10.0]);
+        d.observe(0.5);
+        d.observe(1.0);
+        d.observe(5.0);
+        d.observe(50.0);
+
+        assert_eq!(56.5, d.sum);
+        assert_eq!(4, d.count);
+        assert_eq!(vec![(1.0, 2), (10.0, 1), (f64::MAX, 1)], d.buckets);
+    }
+
+    #[test]
+    fn test_distribution_scale() {
+        let mut d = Distribution::new(&[1.0, 10.0]);
+        d.observe(0.5);
+        d.observe(5.0);
+        d.observe(50.0);
+        d.scale(2.5);
+
+        assert_eq!(138.75, d.sum);
+        assert_eq!(8, d.count);
+        assert_eq!(vec![(1.0, 3), (10.0, 2), (f64::MAX, 3)], d.buckets);
+        assert_eq!(d.count, d.buckets.iter().map(|(_, c)| c).sum::<u64>());
+    }
+
+    #[test]
+    fn test_aggregator_no_ttl() {
+        let group = RuleGroup::default();
+        let aggregator = Aggregator::new(&group, UNIX_EPOCH + Duration::from_secs(1000));
+        let mut counts = LabelCounts::default();
+
+        aggregator.add(&mut counts, &new_meta(-1, 10));
+        aggregator.add(&mut counts, &new_meta(1100, 20));
+
+        assert_eq!(
+            LabelCounts {
+                count: 2,
+                size: 30,
+                ttl: None,
+                item_sizes: None,
+                keys: None,
+            },
+            counts
+        );
+    }
+
+    #[test]
+    fn test_aggregator_ttl() {
+        let group = RuleGroup {
+            ttl: Some(Ttl {
+                buckets: vec![60.0, 600.0],
+            }),
+            ..Default::default()
+        };
+        let aggregator = Aggregator::new(&group, UNIX_EPOCH + Duration::from_secs(1000));
+        let mut counts = LabelCounts::default();
+
+        aggregator.add(&mut counts, &new_meta(-1, 10));
+        aggregator.add(&mut counts, &new_meta(900, 10));
+        aggregator.add(&mut counts, &new_meta(1030, 10));
+        aggregator.add(&mut counts, &new_meta(1100, 10));
+        aggregator.add(&mut counts, &new_meta(1700, 10));
+
+        let mut expected = TtlCounts::new(&[60.0, 600.0]);
+        expected.no_expiry = 1;
+        expected.expired = 1;
+        expected.remaining = Distribution {
+            sum: 830.0,
+            count: 3,
+            buckets: vec![(60.0, 1), (600.0, 1), (f64::MAX, 1)],
+        };
+
+        assert_eq!(5, counts.count);
+        assert_eq!(Some(expected), counts.ttl);
+    }
+
+    #[test]
+    fn test_aggregator_item_sizes() {
+        let group = RuleGroup {
+            item_sizes: Some(ItemSizes {
+                buckets: vec![100.0, 1000.0],
+            }),
+            ..Default::default()
+        };
+        let aggregator = Aggregator::new(&group, UNIX_EPOCH);
+        let mut counts = LabelCounts::default();
+
+        aggregator.add(&mut counts, &new_meta(-1, 90));
+        aggregator.add(&mut counts, &new_meta(-1, 900));
+        aggregator.add(&mut counts, &new_meta(-1, 9000));
+
+        assert_eq!(
+            Some(Distribution {
+                sum: 9990.0,
+                count: 3,
+                buckets: vec![(100.0, 1), (1000.0, 1), (f64::MAX, 1)],
+            }),
+            counts.item_sizes
+        );
+    }
+
+    #[test]
+    fn test_key_sample_capacity() {
+        let mut sample = KeySample::new(3);
+        for i in 0..100 {
+            let meta = Meta {
+                key: format!("key-{}", i),
+                expires: -1,
+                size: i,
+            };
+            sample.add(&meta, |k| k.to_owned());
+        }
+
+        assert_eq!(100, sample.seen());
+        assert_eq!(3, sample.keys.len());
+        assert!(sample.keys.iter().all(|k| k.key == format!("key-{}", k.size)));
+    }
+
+    #[test]
+    fn test_key_sample_merge() {
+        let mut a = KeySample::new(4);
+        let mut b = KeySample::new(4);
+        for i in 0..2 {
+            a.add(&new_meta(-1, i), |k| k.to_owned());
+        }
+        for i in 0..10 {
+            b.add(&new_meta(-1, i), |k| k.to_owned());
+        }
+
+        a.merge(&b);
+        assert_eq!(12, a.seen());
+        assert_eq!(4, a.keys.len());
+    }
+
+    #[test]
+    fn test_aggregator_key_samples_redact() {
+        let group = RuleGroup {
+            key_samples: Some(KeySamples {
+                size: 2,
+                redact: vec![RulePattern::new(r"user-\d+").unwrap()],
+            }),
+            ..Default::default()
+        };
+        let aggregator = Aggregator::new(&group, UNIX_EPOCH);
+        let mut counts = LabelCounts::default();
+        let meta = Meta {
+            key: "cart:user-123:v1".to_owned(),
+            expires: 1700000000,
+            size: 64,
+        };
+
+        aggregator.add(&mut counts, &meta);
+
+        let keys = counts.keys.unwrap().keys;
+        assert_eq!(1, keys.len());
+        assert_eq!(format!("cart:{}:v1", REDACTED), keys[0].key);
+        assert_eq!(64, keys[0].size);
+        assert_eq!(1700000000, keys[0].expires);
+    }
+}
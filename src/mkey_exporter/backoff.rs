@@ -0,0 +1,85 @@
+use crate::sample::random_u64;
+use std::time::Duration;
+
+/// How many times to retry a failed update and how long to wait between attempts.
+///
+/// The wait doubles after each attempt, up to a maximum, and is randomized so that many
+/// exporters that fail at the same time don't retry at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub retries: u32,
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Time to wait before retry number `retry`, starting at 0. The result is between half
+    /// and all of `initial * 2^retry`, capped at `max`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let base = self
+            .initial
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(self.max)
+            .min(self.max);
+        let half = base / 2;
+        half + jitter(base - half)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Random duration between zero (inclusive) and `max` (exclusive), or zero if `max` is zero.
+pub fn jitter(max: Duration) -> Duration {
+    let nanos = max.as_nanos().min(u64::MAX as u128) as u64;
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+
+    Duration::from_nanos(random_u64() % nanos)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{jitter, Backoff};
+    use std::time::Duration;
+
+    #[test]
+    fn test_jitter() {
+        assert_eq!(Duration::ZERO, jitter(Duration::ZERO));
+
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_millis(10)) < Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            retries: 5,
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+        };
+
+        for _ in 0..100 {
+            let d = backoff.delay(0);
+            assert!(d >= Duration::from_millis(500) && d < Duration::from_secs(1));
+
+            let d = backoff.delay(2);
+            assert!(d >= Duration::from_secs(2) && d < Duration::from_secs(4));
+
+            let d = backoff.delay(10);
+            assert!(d >= Duration::from_millis(2500) && d < Duration::from_secs(5));
+
+            let d = backoff.delay(u32::MAX);
+            assert!(d >= Duration::from_millis(2500) && d < Duration::from_secs(5));
+        }
+    }
+}
@@ -1,29 +1,538 @@
+use crate::metrics::SERVER_LABEL;
 use regex::Regex;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 
+/// Parse and validate a `RuleGroup` from the YAML file at `path`.
 pub fn from_path(path: &PathBuf) -> Result<RuleGroup, io::Error> {
+    let group = parse_path(path)?;
+    group
+        .validate()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(group)
+}
+
+/// Parse a `RuleGroup` from the YAML file at `path` without validating it.
+pub fn parse_path(path: &PathBuf) -> Result<RuleGroup, io::Error> {
     let reader = File::open(path)?;
     let group = serde_yaml::from_reader(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
     Ok(group)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RuleGroup {
     pub name: String,
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
     pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub drop_unmatched: bool,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub ttl: Option<Ttl>,
+    #[serde(default)]
+    pub item_sizes: Option<ItemSizes>,
+    #[serde(default)]
+    pub sampling: Option<Sampling>,
+    #[serde(default)]
+    pub key_samples: Option<KeySamples>,
+    #[serde(default)]
+    pub tests: Vec<RuleTest>,
+}
+
+impl RuleGroup {
+    /// Check that every rule will emit valid Prometheus labels, returning all problems
+    /// found instead of stopping at the first one.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+
+        for (i, label) in self.labels.iter().enumerate() {
+            if let Err(msg) = validate_label_name(&label.name) {
+                errors.push(ValidationError::group(format!("labels[{}]: {}", i, msg)));
+            }
+
+            if self.labels[..i].iter().any(|l| l.name == label.name) {
+                errors.push(ValidationError::group(format!(
+                    "labels[{}]: label {:?} is declared more than once",
+                    i, label.name
+                )));
+            }
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.action == Action::Drop {
+                if !rule.label_name.is_empty() || !rule.label_value.is_empty() {
+                    errors.push(ValidationError::rule(
+                        i,
+                        "drop rules may not set label_name or label_value",
+                    ));
+                }
+
+                continue;
+            }
+
+            if let Err(msg) = validate_label_name(&rule.label_name) {
+                errors.push(ValidationError::rule(i, msg));
+            }
+
+            if !self.labels.is_empty() && !self.labels.iter().any(|l| l.name == rule.label_name) {
+                errors.push(ValidationError::rule(
+                    i,
+                    format!("label_name {:?} is not declared in labels", rule.label_name),
+                ));
+            }
+
+            for reference in references(&rule.label_value) {
+                if !reference.exists_in(&rule.pattern) {
+                    errors.push(ValidationError::rule(
+                        i,
+                        format!(
+                            "label_value {:?} references capture group {} which does not exist in pattern {:?}",
+                            rule.label_value,
+                            reference,
+                            rule.pattern.as_str()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if let Some(0) = self.limits.max_series {
+            errors.push(ValidationError::group("limits: max_series must be at least 1"));
+        }
+
+        for (name, max) in self.limits.max_values.iter() {
+            if *max == 0 {
+                errors.push(ValidationError::group(format!(
+                    "limits: max_values for label {:?} must be at least 1",
+                    name
+                )));
+            }
+
+            if !self
+                .rules
+                .iter()
+                .any(|r| r.action == Action::Label && &r.label_name == name)
+            {
+                errors.push(ValidationError::group(format!(
+                    "limits: max_values for label {:?} is not set by any rule",
+                    name
+                )));
+            }
+        }
+
+        if self.limits.overflow_value.is_empty() {
+            errors.push(ValidationError::group("limits: overflow_value may not be empty"));
+        }
+
+        if let Some(ttl) = &self.ttl {
+            if let Err(msg) = validate_buckets(&ttl.buckets) {
+                errors.push(ValidationError::group(format!("ttl: {}", msg)));
+            }
+        }
+
+        if let Some(sizes) = &self.item_sizes {
+            if let Err(msg) = validate_buckets(&sizes.buckets) {
+                errors.push(ValidationError::group(format!("item_sizes: {}", msg)));
+            }
+        }
+
+        if let Some(sampling) = &self.sampling {
+            match (sampling.rate, sampling.max_keys) {
+                (Some(rate), None) if !(rate > 0.0 && rate <= 1.0) => {
+                    errors.push(ValidationError::group(
+                        "sampling: rate must be greater than 0 and at most 1",
+                    ));
+                }
+                (None, Some(0)) => {
+                    errors.push(ValidationError::group("sampling: max_keys must be at least 1"));
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => {
+                    errors.push(ValidationError::group(
+                        "sampling: exactly one of rate or max_keys must be set",
+                    ));
+                }
+            }
+        }
+
+        if let Some(KeySamples { size: 0, .. }) = &self.key_samples {
+            errors.push(ValidationError::group("key_samples: size must be at least 1"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(errors))
+        }
+    }
+}
+
+/// A single problem with a `RuleGroup`, optionally associated with a particular rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub rule: Option<usize>,
+    pub msg: String,
+}
+
+impl ValidationError {
+    pub fn rule<S>(index: usize, msg: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            rule: Some(index),
+            msg: msg.into(),
+        }
+    }
+
+    pub fn group<S>(msg: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            rule: None,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(i) = self.rule {
+            write!(f, "rules[{}]: {}", i, self.msg)
+        } else {
+            write!(f, "{}", self.msg)
+        }
+    }
+}
+
+/// All problems found while validating a `RuleGroup`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl Deref for ValidationErrors {
+    type Target = Vec<ValidationError>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", e)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for ValidationErrors {}
+
+/// Check that histogram bucket bounds are positive and strictly increasing.
+fn validate_buckets(buckets: &[f64]) -> Result<(), String> {
+    if buckets.is_empty() {
+        return Err("buckets may not be empty".to_owned());
+    }
+
+    if buckets.iter().any(|b| !b.is_finite() || *b <= 0.0) {
+        return Err(format!("buckets {:?} must all be positive numbers", buckets));
+    }
+
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(format!("buckets {:?} must be in increasing order", buckets));
+    }
+
+    Ok(())
+}
+
+/// Check that `name` is a valid Prometheus label name that doesn't conflict with
+/// labels reserved by Prometheus or added by the exporter.
+fn validate_label_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    };
+
+    if !valid {
+        Err(format!(
+            "label_name {:?} is not a valid Prometheus label name, must match [a-zA-Z_][a-zA-Z0-9_]*",
+            name
+        ))
+    } else if name.starts_with("__") {
+        Err(format!(
+            "label_name {:?} is reserved, names starting with '__' are for internal use",
+            name
+        ))
+    } else if name == SERVER_LABEL {
+        Err(format!("label_name {:?} is reserved, it is added to all series", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reference to a capture group in a `label_value` such as `$1`, `$name`, or `${name}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reference<'a> {
+    Index(usize),
+    Name(&'a str),
+}
+
+impl<'a> Reference<'a> {
+    fn exists_in(&self, pattern: &Regex) -> bool {
+        match self {
+            Reference::Index(i) => *i < pattern.captures_len(),
+            Reference::Name(n) => pattern.capture_names().flatten().any(|c| c == *n),
+        }
+    }
+}
+
+impl<'a> Display for Reference<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Index(i) => write!(f, "${}", i),
+            Reference::Name(n) => write!(f, "${{{}}}", n),
+        }
+    }
+}
+
+/// Find all capture group references in `replacement` following the same rules used by
+/// `regex::Captures::expand`: `$$` is a literal `$`, `${name}` is a reference to `name`,
+/// and `$name` is a reference to the longest sequence of `[_0-9a-zA-Z]` following the `$`.
+/// Names consisting only of digits refer to groups by index.
+fn references(replacement: &str) -> Vec<Reference<'_>> {
+    let mut out = Vec::new();
+    let mut rest = replacement;
+
+    while let Some(pos) = rest.find('$') {
+        rest = &rest[pos + 1..];
+
+        if let Some(r) = rest.strip_prefix('$') {
+            rest = r;
+            continue;
+        }
+
+        let name = if let Some(r) = rest.strip_prefix('{') {
+            match r.find('}') {
+                Some(end) => {
+                    rest = &r[end + 1..];
+                    &r[..end]
+                }
+                None => continue,
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+
+        if name.is_empty() {
+            continue;
+        }
+
+        out.push(match name.parse::<usize>() {
+            Ok(i) => Reference::Index(i),
+            Err(_) => Reference::Name(name),
+        });
+    }
+
+    out
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rule {
     pub pattern: RulePattern,
+    #[serde(default)]
+    pub action: Action,
+    #[serde(default)]
     pub label_name: String,
+    #[serde(default)]
     pub label_value: String,
 }
 
+/// What to do with keys matching a rule: set a label based on the key or drop the
+/// key so that it is not included in any counts or sizes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Label,
+    Drop,
+}
+
+/// Label that every series will have, in the order declared, using `default` as the
+/// value when no rule sets it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub name: String,
+    #[serde(default)]
+    pub default: String,
+}
+
+/// Limits on the number of series emitted to protect Prometheus from label values with
+/// unexpectedly high cardinality. Label values and label sets beyond the limits are combined
+/// into a single series using `overflow_value`, keeping the largest by `rank_by` as-is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    #[serde(default)]
+    pub max_series: Option<usize>,
+    #[serde(default)]
+    pub max_values: BTreeMap<String, usize>,
+    #[serde(default = "default_overflow_value")]
+    pub overflow_value: String,
+    #[serde(default)]
+    pub rank_by: RankBy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_series: None,
+            max_values: BTreeMap::new(),
+            overflow_value: default_overflow_value(),
+            rank_by: RankBy::default(),
+        }
+    }
+}
+
+fn default_overflow_value() -> String {
+    "__other__".to_owned()
+}
+
+/// How to pick which label values or label sets to keep when limiting series.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RankBy {
+    #[default]
+    Count,
+    Size,
+}
+
+/// Settings for exporting the distribution of expiration times of keys for each label set.
+/// Buckets are upper bounds of the remaining time-to-live of keys, in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ttl {
+    #[serde(default = "default_ttl_buckets")]
+    pub buckets: Vec<f64>,
+}
+
+impl Default for Ttl {
+    fn default() -> Self {
+        Self {
+            buckets: default_ttl_buckets(),
+        }
+    }
+}
+
+fn default_ttl_buckets() -> Vec<f64> {
+    // One minute up to 30 days, the longest relative TTL allowed by Memcached.
+    vec![
+        60.0, 300.0, 900.0, 1800.0, 3600.0, 10800.0, 21600.0, 43200.0, 86400.0, 604800.0, 2592000.0,
+    ]
+}
+
+/// Settings for exporting the distribution of sizes of keys for each label set. Buckets are
+/// upper bounds of item sizes, in bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemSizes {
+    #[serde(default = "default_size_buckets")]
+    pub buckets: Vec<f64>,
+}
+
+impl Default for ItemSizes {
+    fn default() -> Self {
+        Self {
+            buckets: default_size_buckets(),
+        }
+    }
+}
+
+fn default_size_buckets() -> Vec<f64> {
+    // Match the default chunk sizes of Memcached slab classes: starting from 96 bytes,
+    // each class is 1.25 times larger than the previous, aligned to 8 bytes, up to the
+    // largest chunk size of 512KB. Larger items are split across multiple chunks so the
+    // last bucket is the default maximum item size of 1MB.
+    const FACTOR: f64 = 1.25;
+    const ALIGN: u64 = 8;
+    const CHUNK_MAX: u64 = 512 * 1024;
+    const ITEM_MAX: u64 = 1024 * 1024;
+
+    let mut out = Vec::new();
+    let mut size: u64 = 96;
+    while size < CHUNK_MAX {
+        out.push(size as f64);
+        size = ((size as f64 * FACTOR) as u64).div_ceil(ALIGN) * ALIGN;
+    }
+
+    out.push(CHUNK_MAX as f64);
+    out.push(ITEM_MAX as f64);
+    out
+}
+
+/// Settings for only processing a sample of keys, chosen by a hash of each key so that the
+/// same keys are picked every update. Counts and sizes are scaled up to estimate totals for
+/// all keys. Either a fixed `rate` or a `max_keys` budget per update may be set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sampling {
+    #[serde(default)]
+    pub rate: Option<f64>,
+    #[serde(default)]
+    pub max_keys: Option<u64>,
+}
+
+/// Settings for keeping a random sample of up to `size` keys for each label set to show
+/// example keys behind a label set. Parts of keys matching any of the `redact` patterns are
+/// replaced before keys are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySamples {
+    #[serde(default = "default_key_samples_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub redact: Vec<RulePattern>,
+}
+
+impl Default for KeySamples {
+    fn default() -> Self {
+        Self {
+            size: default_key_samples_size(),
+            redact: Vec::new(),
+        }
+    }
+}
+
+fn default_key_samples_size() -> usize {
+    5
+}
+
+/// Example key and the exact set of labels that rules are expected to produce for it, or
+/// if the key is expected to be dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleTest {
+    pub key: String,
+    #[serde(default)]
+    pub dropped: bool,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
 #[derive(Debug, Clone)]
 #[repr(transparent)]
 pub struct RulePattern(Regex);
@@ -68,3 +577,160 @@ impl DerefMut for RulePattern {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        default_size_buckets, references, Action, KeySamples, Label, Reference, Rule, RuleGroup, RulePattern, Sampling,
+        ValidationError,
+    };
+
+    fn new_group(pattern: &str, label_name: &str, label_value: &str) -> RuleGroup {
+        RuleGroup {
+            name: "test".to_owned(),
+            rules: vec![Rule {
+                pattern: RulePattern::new(pattern).unwrap(),
+                action: Action::Label,
+                label_name: label_name.to_owned(),
+                label_value: label_value.to_owned(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(Vec::<Reference>::new(), references("plain"));
+        assert_eq!(Vec::<Reference>::new(), references("$$1 and $"));
+        assert_eq!(vec![Reference::Index(1)], references("u$1"));
+        assert_eq!(vec![Reference::Name("1a")], references("$1a"));
+        assert_eq!(
+            vec![Reference::Index(1), Reference::Name("user")],
+            references("${1}a-${user}")
+        );
+        assert_eq!(vec![Reference::Name("user_id")], references("$user_id-x"));
+    }
+
+    #[test]
+    fn test_validate_success() {
+        let group = new_group(r"^(\w+):(?P<user>\w+):", "type", "$1-${user}");
+        assert!(group.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_invalid_label_name() {
+        let group = new_group(r"^(\w+):", "1type", "$1");
+        let errors = group.validate().unwrap_err();
+        assert_eq!(1, errors.len());
+        assert_eq!(Some(0), errors[0].rule);
+    }
+
+    #[test]
+    fn test_validate_reserved_label_name() {
+        assert!(new_group(r"^(\w+):", "__type", "$1").validate().is_err());
+        assert!(new_group(r"^(\w+):", "server", "$1").validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_missing_capture_groups() {
+        let group = new_group(r"^(\w+):", "type", "$2-${user}-$1");
+        let errors = group.validate().unwrap_err();
+        assert_eq!(
+            vec![
+                ValidationError::rule(
+                    0,
+                    r#"label_value "$2-${user}-$1" references capture group $2 which does not exist in pattern "^(\\w+):""#
+                ),
+                ValidationError::rule(
+                    0,
+                    r#"label_value "$2-${user}-$1" references capture group ${user} which does not exist in pattern "^(\\w+):""#
+                ),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_validate_declared_labels() {
+        let mut group = new_group(r"^(\w+):", "type", "$1");
+        group.labels = vec![
+            Label {
+                name: "user".to_owned(),
+                default: "unknown".to_owned(),
+            },
+            Label {
+                name: "user".to_owned(),
+                default: "".to_owned(),
+            },
+        ];
+
+        let errors = group.validate().unwrap_err();
+        assert_eq!(
+            vec![
+                ValidationError::group(r#"labels[1]: label "user" is declared more than once"#),
+                ValidationError::rule(0, r#"label_name "type" is not declared in labels"#),
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_validate_drop_rule() {
+        let mut group = new_group(r"^lock:", "", "");
+        group.rules[0].action = Action::Drop;
+        assert!(group.validate().is_ok());
+
+        group.rules[0].label_name = "type".to_owned();
+        let errors = group.validate().unwrap_err();
+        assert_eq!(
+            vec![ValidationError::rule(
+                0,
+                "drop rules may not set label_name or label_value"
+            )],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn test_validate_sampling() {
+        let mut group = new_group(r"^(\w+):", "type", "$1");
+        for (rate, max_keys, ok) in [
+            (Some(0.01), None, true),
+            (Some(1.0), None, true),
+            (None, Some(1000), true),
+            (Some(0.0), None, false),
+            (Some(1.5), None, false),
+            (Some(f64::NAN), None, false),
+            (None, Some(0), false),
+            (None, None, false),
+            (Some(0.5), Some(1000), false),
+        ] {
+            group.sampling = Some(Sampling { rate, max_keys });
+            assert_eq!(ok, group.validate().is_ok(), "rate {:?}, max_keys {:?}", rate, max_keys);
+        }
+    }
+
+    #[test]
+    fn test_validate_key_samples() {
+        let mut group = new_group(r"^(\w+):", "type", "$1");
+        group.key_samples = Some(KeySamples::default());
+        assert!(group.validate().is_ok());
+
+        group.key_samples = Some(KeySamples {
+            size: 0,
+            redact: Vec::new(),
+        });
+        assert_eq!(
+            vec![ValidationError::group("key_samples: size must be at least 1")],
+            group.validate().unwrap_err().0
+        );
+    }
+
+    #[test]
+    fn test_default_size_buckets() {
+        let buckets = default_size_buckets();
+        assert_eq!(&[96.0, 120.0, 152.0, 192.0, 240.0, 304.0], &buckets[..6]);
+        assert_eq!(&[524288.0, 1048576.0], &buckets[buckets.len() - 2..]);
+        assert!(super::validate_buckets(&buckets).is_ok());
+    }
+}
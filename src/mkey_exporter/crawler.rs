@@ -0,0 +1,476 @@
+//! Streaming connections to Memcached for the `lru_crawler metadump` command.
+//!
+//! This duplicates the TLS setup and connection pooling of `mtop_client::MemcachedPool`.
+//! `mtop_client` only returns metadata for keys once every key has been read into memory,
+//! which doesn't work for caches with tens of millions of keys, and doesn't expose its
+//! connections or TLS configuration to build on. It's kept to the minimum needed to stream
+//! keys: it takes the same `TLSConfig` and uses the same versions of `tokio-rustls`,
+//! `rustls-pemfile`, and `webpki-roots` as `mtop_client` so no additional crates are built.
+//! It should be replaced by a streaming API in `mtop_client` once one is available.
+
+use crate::source::MetaSink;
+use mtop_client::{Meta, MtopError, TLSConfig};
+use rustls_pemfile::Item;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+const ERROR_PREFIXES: &[&str] = &["ERROR", "CLIENT_ERROR", "SERVER_ERROR", "BUSY", "BADCLASS"];
+
+/// Pool of connections to Memcached servers used to crawl metadata for every key.
+///
+/// Unlike `mtop_client::MemcachedPool`, connections from this pool hand each key to the
+/// caller as it is read from the server instead of buffering every key in memory first.
+#[derive(Debug)]
+pub struct CrawlerPool {
+    tls: Option<(Arc<ClientConfig>, Option<ServerName>)>,
+    crawlers: Mutex<HashMap<String, Crawler>>,
+}
+
+impl CrawlerPool {
+    pub async fn new(tls: &TLSConfig) -> Result<Self, MtopError> {
+        let tls = if tls.enabled {
+            let name = tls.server_name.as_deref().map(server_name).transpose()?;
+            Some((Arc::new(client_config(tls).await?), name))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            tls,
+            crawlers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get an existing connection to `host` if one is available and still usable, or
+    /// create a new connection.
+    pub async fn get(&self, host: &str) -> Result<Crawler, MtopError> {
+        let existing = self.crawlers.lock().await.remove(host);
+        if let Some(mut c) = existing {
+            // The server may have closed the connection since it was last used.
+            if c.ping().await.is_ok() {
+                return Ok(c);
+            }
+        }
+
+        self.connect(host).await
+    }
+
+    /// Return a connection to the pool so that it may be reused. Connections that returned
+    /// an error should be dropped instead since they may be in the middle of a response.
+    pub async fn put(&self, crawler: Crawler) {
+        self.crawlers.lock().await.insert(crawler.host.clone(), crawler);
+    }
+
+    async fn connect(&self, host: &str) -> Result<Crawler, MtopError> {
+        let tcp = TcpStream::connect(host)
+            .await
+            // Writes are buffered and flushed so we don't need delay to avoid tiny packets.
+            .and_then(|s| s.set_nodelay(true).map(|_| s))
+            .map_err(|e| MtopError::from((host.to_owned(), e)))?;
+
+        match &self.tls {
+            Some((config, name)) => {
+                let name = match name {
+                    Some(n) => n.clone(),
+                    None => server_name(host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host))?,
+                };
+
+                let stream = TlsConnector::from(config.clone())
+                    .connect(name, tcp)
+                    .await
+                    .map_err(|e| MtopError::from((host.to_owned(), e)))?;
+                let (read, write) = tokio::io::split(stream);
+                Ok(Crawler::new(host, read, write))
+            }
+            None => {
+                let (read, write) = tcp.into_split();
+                Ok(Crawler::new(host, read, write))
+            }
+        }
+    }
+}
+
+/// Connection to a single Memcached server that streams the results of the
+/// `lru_crawler metadump` command.
+pub struct Crawler {
+    host: String,
+    read: BufReader<Box<dyn AsyncRead + Send + Sync + Unpin>>,
+    write: BufWriter<Box<dyn AsyncWrite + Send + Sync + Unpin>>,
+    line: String,
+}
+
+impl Crawler {
+    fn new<R, W>(host: &str, read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Sync + Unpin + 'static,
+        W: AsyncWrite + Send + Sync + Unpin + 'static,
+    {
+        Self {
+            host: host.to_owned(),
+            read: BufReader::new(Box::new(read)),
+            write: BufWriter::new(Box::new(write)),
+            line: String::new(),
+        }
+    }
+
+    /// Make sure the server is responding to commands.
+    pub async fn ping(&mut self) -> Result<(), MtopError> {
+        self.send("version\r\n").await?;
+        let line = self.next_line().await?;
+        if line.starts_with("VERSION") {
+            Ok(())
+        } else {
+            Err(MtopError::internal(format!("unexpected response '{}'", line)))
+        }
+    }
+
+    /// Get the number of items currently stored by the server.
+    pub async fn num_items(&mut self) -> Result<u64, MtopError> {
+        self.send("stats\r\n").await?;
+        let mut items = None;
+
+        loop {
+            let line = self.next_line().await?;
+            if line == "END" {
+                break;
+            }
+
+            match line.split(' ').collect::<Vec<_>>()[..] {
+                ["STAT", "curr_items", v] => {
+                    items = Some(
+                        v.parse()
+                            .map_err(|e| MtopError::internal_cause(format!("invalid curr_items '{}'", v), e))?,
+                    );
+                }
+                ["STAT", _, _] => {}
+                _ => return Err(MtopError::internal(format!("unexpected response '{}'", line))),
+            }
+        }
+
+        items.ok_or_else(|| MtopError::internal("missing curr_items stat"))
+    }
+
+    /// Add metadata for every key on the server to `sink` as it is read, returning the
+    /// number of keys read. The same `Meta` is reused for each key to avoid allocating.
+    pub async fn metas<S>(&mut self, sink: &mut S) -> Result<u64, MtopError>
+    where
+        S: MetaSink,
+    {
+        self.send("lru_crawler metadump hash\r\n").await?;
+        let mut meta = Meta::default();
+        let mut num_keys = 0;
+
+        loop {
+            let line = self.next_line().await?;
+            if line == "END" {
+                return Ok(num_keys);
+            }
+
+            parse_line(line, &mut meta).map_err(MtopError::internal)?;
+            sink.add(&meta).await;
+            num_keys += 1;
+        }
+    }
+
+    async fn send(&mut self, cmd: &str) -> Result<(), MtopError> {
+        self.write.write_all(cmd.as_bytes()).await?;
+        Ok(self.write.flush().await?)
+    }
+
+    async fn next_line(&mut self) -> Result<&str, MtopError> {
+        self.line.clear();
+        if self.read.read_line(&mut self.line).await? == 0 {
+            return Err(MtopError::internal(format!("connection to {} closed", self.host)));
+        }
+
+        Ok(self.line.trim_end_matches(['\r', '\n']))
+    }
+}
+
+impl Debug for Crawler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Crawler").field("host", &self.host).finish()
+    }
+}
+
+/// Parse a single line of `lru_crawler metadump` output into `meta`, reusing the existing
+/// key to avoid allocating. Fields other than `key`, `exp`, and `size` are ignored.
+pub(crate) fn parse_line(line: &str, meta: &mut Meta) -> Result<(), String> {
+    // The metadump command doesn't have a prefix for each result line so check for an
+    // error response before trying to parse the line as key-value pairs.
+    if ERROR_PREFIXES.iter().any(|p| line.split(' ').next() == Some(p)) {
+        return Err(format!("server returned error '{}'", line));
+    }
+
+    let (mut key, mut exp, mut size) = (false, false, false);
+    for p in line.split(' ') {
+        let (name, val) = p
+            .split_once('=')
+            .ok_or_else(|| format!("unexpected metadump format '{}'", line))?;
+
+        match name {
+            "key" => {
+                let decoded = urlencoding::decode(val).map_err(|e| format!("invalid key encoding '{}': {}", val, e))?;
+                meta.key.clear();
+                meta.key.push_str(&decoded);
+                key = true;
+            }
+            "exp" => {
+                meta.expires = val.parse().map_err(|e| format!("invalid exp '{}': {}", val, e))?;
+                exp = true;
+            }
+            "size" => {
+                meta.size = val.parse().map_err(|e| format!("invalid size '{}': {}", val, e))?;
+                size = true;
+            }
+            _ => {}
+        }
+    }
+
+    if key && exp && size {
+        Ok(())
+    } else {
+        Err(format!("missing key, exp, or size in '{}'", line))
+    }
+}
+
+fn server_name(host: &str) -> Result<ServerName, MtopError> {
+    ServerName::try_from(host).map_err(|e| MtopError::configuration_cause(format!("invalid server name '{}'", host), e))
+}
+
+async fn client_config(tls: &TLSConfig) -> Result<ClientConfig, MtopError> {
+    let mut roots = RootCertStore::empty();
+    match &tls.ca_path {
+        Some(path) => {
+            for cert in load_certs(path).await? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| MtopError::configuration_cause(format!("unable to use CA cert {:?}", path), e))?;
+            }
+        }
+        None => {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+            }))
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    match (&tls.cert_path, &tls.key_path) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert).await?, load_key(key).await?)
+            .map_err(|e| MtopError::configuration_cause("unable to use client cert or key", e)),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+async fn load_certs(path: &Path) -> Result<Vec<Certificate>, MtopError> {
+    let raw = tokio::fs::read(path)
+        .await
+        .map_err(|e| MtopError::configuration_cause(format!("unable to load cert {:?}", path), e))?;
+
+    rustls_pemfile::certs(&mut raw.as_slice())
+        .map(|certs| certs.into_iter().map(Certificate).collect())
+        .map_err(|e| MtopError::configuration_cause(format!("unable to parse cert {:?}", path), e))
+}
+
+async fn load_key(path: &Path) -> Result<PrivateKey, MtopError> {
+    let raw = tokio::fs::read(path)
+        .await
+        .map_err(|e| MtopError::configuration_cause(format!("unable to load key {:?}", path), e))?;
+
+    parse_key(&raw, path)
+}
+
+/// Get the first PKCS#8, PKCS#1 (RSA), or SEC1 (EC) private key from PEM encoded `raw`.
+fn parse_key(raw: &[u8], path: &Path) -> Result<PrivateKey, MtopError> {
+    rustls_pemfile::read_all(&mut &raw[..])
+        .map_err(|e| MtopError::configuration_cause(format!("unable to parse key {:?}", path), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| MtopError::configuration(format!("no keys available in {:?}", path)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_key, parse_line, Crawler};
+    use mtop_client::Meta;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_key() {
+        let path = Path::new("client.key");
+        for kind in ["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"] {
+            let pem = format!("-----BEGIN {0}-----\nAQID\n-----END {0}-----\n", kind);
+            assert_eq!(vec![1, 2, 3], parse_key(pem.as_bytes(), path).unwrap().0, "{}", kind);
+        }
+
+        let cert = "-----BEGIN CERTIFICATE-----\nAQID\n-----END CERTIFICATE-----\n";
+        assert!(parse_key(cert.as_bytes(), path).is_err());
+        assert!(parse_key(b"", path).is_err());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let mut meta = Meta::default();
+        parse_line(
+            "key=cart%3Auser-1 exp=-1 la=1700000000 cas=1 fetch=no cls=1 size=64",
+            &mut meta,
+        )
+        .unwrap();
+
+        assert_eq!("cart:user-1", meta.key);
+        assert_eq!(-1, meta.expires);
+        assert_eq!(64, meta.size);
+    }
+
+    #[test]
+    fn test_parse_line_decode_key() {
+        let mut meta = Meta::default();
+        parse_line("key=user%20name%2F%25+1 exp=0 size=1", &mut meta).unwrap();
+        assert_eq!("user name/%+1", meta.key);
+
+        parse_line("key=%E2%9C%93 exp=0 size=1", &mut meta).unwrap();
+        assert_eq!("\u{2713}", meta.key);
+    }
+
+    #[test]
+    fn test_parse_line_error() {
+        let mut meta = Meta::default();
+        assert!(parse_line("BUSY currently processing crawler request", &mut meta).is_err());
+        assert!(parse_line("ERROR", &mut meta).is_err());
+        assert!(parse_line("key=cart exp=-1", &mut meta).is_err());
+        assert!(parse_line("key=cart exp=never size=64", &mut meta).is_err());
+    }
+
+    #[test]
+    fn test_parse_line_error_message() {
+        let mut meta = Meta::default();
+        let err = |line: &str, meta: &mut Meta| parse_line(line, meta).unwrap_err();
+
+        assert!(err("SERVER_ERROR out of memory", &mut meta).starts_with("server returned error"));
+        assert!(err("CLIENT_ERROR bad command line format", &mut meta).starts_with("server returned error"));
+        assert!(err("BADCLASS invalid class id", &mut meta).starts_with("server returned error"));
+        assert!(err("key=cart exp=-1 garbage size=64", &mut meta).starts_with("unexpected metadump format"));
+        assert!(err("", &mut meta).starts_with("unexpected metadump format"));
+        assert!(err("key=%FF exp=-1 size=64", &mut meta).starts_with("invalid key encoding"));
+        assert!(err("key=cart exp=-1 size=big", &mut meta).starts_with("invalid size"));
+        assert!(err("key=cart exp= size=64", &mut meta).starts_with("invalid exp"));
+        assert!(err("exp=-1 size=64", &mut meta).starts_with("missing key, exp, or size"));
+        assert!(err("key=cart exp=-1 la=1 cas=1", &mut meta).starts_with("missing key, exp, or size"));
+    }
+
+    #[tokio::test]
+    async fn test_metas() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(client);
+        let mut crawler = Crawler::new("localhost:11211", read, write);
+
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0; 64];
+            let n = server.read(&mut buf).await.unwrap();
+            assert_eq!(b"lru_crawler metadump hash\r\n", &buf[..n]);
+            server
+                .write_all(b"key=a%3A1 exp=-1 size=10\r\nkey=b%3A2 exp=1700000000 size=20\r\nEND\r\n")
+                .await
+                .unwrap();
+        });
+
+        let mut keys = Vec::new();
+        let num_keys = crawler.metas(&mut |m: &Meta| keys.push(m.clone())).await.unwrap();
+
+        assert_eq!(2, num_keys);
+        assert_eq!("a:1", keys[0].key);
+        assert_eq!(10, keys[0].size);
+        assert_eq!("b:2", keys[1].key);
+        assert_eq!(1700000000, keys[1].expires);
+    }
+
+    #[tokio::test]
+    async fn test_num_items() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(client);
+        let mut crawler = Crawler::new("localhost:11211", read, write);
+
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0; 64];
+            let n = server.read(&mut buf).await.unwrap();
+            assert_eq!(b"stats\r\n", &buf[..n]);
+            server
+                .write_all(b"STAT pid 1\r\nSTAT curr_items 42\r\nSTAT version 1.6.21\r\nEND\r\n")
+                .await
+                .unwrap();
+        });
+
+        assert_eq!(42, crawler.num_items().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_metas_error_response() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(client);
+        let mut crawler = Crawler::new("localhost:11211", read, write);
+
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0; 64];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"key=a%3A1 exp=-1 size=10\r\nSERVER_ERROR out of memory\r\n")
+                .await
+                .unwrap();
+        });
+
+        let mut keys = Vec::new();
+        let err = crawler.metas(&mut |m: &Meta| keys.push(m.clone())).await.unwrap_err();
+
+        assert_eq!(1, keys.len());
+        assert!(err.to_string().contains("SERVER_ERROR out of memory"));
+    }
+
+    #[tokio::test]
+    async fn test_metas_malformed_response() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(client);
+        let mut crawler = Crawler::new("localhost:11211", read, write);
+
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0; 64];
+            let _ = server.read(&mut buf).await.unwrap();
+            server.write_all(b"VALUE a 0 1\r\nx\r\nEND\r\n").await.unwrap();
+        });
+
+        let err = crawler.metas(&mut |_: &Meta| {}).await.unwrap_err();
+        assert!(err.to_string().contains("unexpected metadump format"));
+    }
+
+    #[tokio::test]
+    async fn test_metas_closed() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(client);
+        let mut crawler = Crawler::new("localhost:11211", read, write);
+        drop(server);
+
+        assert!(crawler.metas(&mut |_: &Meta| {}).await.is_err());
+    }
+}
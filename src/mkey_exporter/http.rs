@@ -1,26 +1,119 @@
+use crate::metrics::Metrics;
 use crate::profile::Profiler;
-use axum::extract::State;
-use axum::http::header::CONTENT_TYPE;
+use crate::status::{self, ServerStatus};
+use crate::summary::{self, LabelSetKeys, Summary, SummaryQuery, UnmatchedKeys};
+use crate::updater::Updater;
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
-use axum::response::IntoResponse;
-use prometheus_client::encoding::text;
-use prometheus_client::registry::Registry;
-use std::sync::Arc;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
 
 const OCTET_STREAM: &str = "application/octet-stream";
+const PLAIN_TEXT: &str = "text/plain; charset=utf-8";
 const METRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
 #[derive(Debug)]
 pub struct RequestState {
-    pub registry: Registry,
+    pub metrics: Arc<Metrics>,
+    pub updater: Arc<Updater>,
+    /// When set, metrics are updated by scrapes if older than this instead of at a fixed
+    /// interval.
+    pub max_age: Option<Duration>,
+    /// Limits how often updates can be triggered by `POST /-/refresh`.
+    pub refresh_limit: RateLimit,
     pub profiler: Profiler,
 }
 
+/// Allow an action at most once per interval.
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns `Ok` if the action is allowed now, counting it as the most recent one, or
+    /// `Err` with how long to wait until it is allowed.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        match *last {
+            Some(t) if now.duration_since(t) < self.interval => Err(self.interval - now.duration_since(t)),
+            _ => {
+                *last = Some(now);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshParams {
+    #[serde(rename = "async")]
+    pub run_async: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeysResponse {
+    label_sets: Vec<LabelSetKeys>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnmatchedResponse {
+    servers: Vec<UnmatchedKeys>,
+}
+
+#[derive(Debug, Serialize)]
+struct RefreshResponse {
+    servers: Vec<RefreshResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SummaryParams {
+    /// Comma separated names of labels to group by.
+    pub group_by: Option<String>,
+    /// Either `count` or `size`.
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Result of a refresh for a single server.
+#[derive(Debug, Serialize)]
+struct RefreshResult {
+    server: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_keys: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_unique_labels: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
 pub async fn text_metrics_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
+    if let Some(max_age) = state.max_age {
+        state.updater.update_stale(max_age).await;
+    }
+
     let mut buf = String::new();
     let mut headers = HeaderMap::new();
 
-    match text::encode(&mut buf, &state.registry) {
+    match state.metrics.encode(&mut buf) {
         Ok(_) => {
             tracing::debug!(message = "encoded prometheus metrics to text format", bytes = buf.len());
             headers.insert(CONTENT_TYPE, HeaderValue::from_static(METRICS_TEXT));
@@ -33,6 +126,134 @@ pub async fn text_metrics_handler(State(state): State<Arc<RequestState>>) -> imp
     }
 }
 
+/// Respond with 200 as long as the process is running and able to handle requests.
+pub async fn healthy_handler() -> impl IntoResponse {
+    (StatusCode::OK, "healthy\n")
+}
+
+/// Respond with 200 once rules are loaded and metrics have been updated for at least one
+/// server, and 503 until then. Rules are always loaded before the server starts so only the
+/// update is checked. When updating on demand, this is 503 until the first scrape updates
+/// a server successfully.
+pub async fn ready_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
+    if state.updater.is_ready() {
+        (StatusCode::OK, "ready\n")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready\n")
+    }
+}
+
+/// Respond with a plain text summary of the loaded rules and the state of updates for each
+/// server, meant for people to read.
+pub async fn status_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
+    let body = status::render(&state.updater.rules(), &state.updater.status(), SystemTime::now());
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(PLAIN_TEXT));
+    (StatusCode::OK, headers, body)
+}
+
+/// Update metrics for every server now instead of waiting for the next scheduled update.
+/// Responds with the result of the update for each server once they finish, or right away
+/// with `202` when the `async` query parameter is `1` or `true`. Responds with `429` if
+/// called again before the rate limit allows.
+pub async fn refresh_handler(State(state): State<Arc<RequestState>>, Query(params): Query<RefreshParams>) -> Response {
+    if let Err(wait) = state.refresh_limit.try_acquire() {
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        let body = Json(serde_json::json!({
+            "error": format!("refresh rate limited, retry in {}s", secs),
+        }));
+        return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, secs.to_string())], body).into_response();
+    }
+
+    if matches!(params.run_async.as_deref(), Some("1") | Some("true")) {
+        let updater = state.updater.clone();
+        tokio::spawn(async move { updater.update_all().await });
+        return (StatusCode::ACCEPTED, Json(serde_json::json!({ "status": "started" }))).into_response();
+    }
+
+    let results = state.updater.update_all().await;
+    let mut statuses: HashMap<String, ServerStatus> = state
+        .updater
+        .status()
+        .into_iter()
+        .map(|s| (s.server.clone(), s))
+        .collect();
+    let servers: Vec<RefreshResult> = results
+        .into_iter()
+        .map(|(server, success)| {
+            let status = statuses.remove(&server);
+            let cycle = status.as_ref().and_then(|s| s.cycle.clone()).filter(|_| success);
+            RefreshResult {
+                duration_seconds: cycle.as_ref().map(|c| c.duration.as_secs_f64()),
+                num_keys: cycle.as_ref().map(|c| c.aggregation.num_keys),
+                num_unique_labels: cycle.as_ref().map(|c| c.aggregation.aggregates.len()),
+                error: status
+                    .and_then(|s| s.last_error)
+                    .filter(|_| !success)
+                    .map(|e| e.message),
+                server,
+                success,
+            }
+        })
+        .collect();
+
+    let code = if servers.iter().all(|s| s.success) {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    (code, Json(RefreshResponse { servers })).into_response()
+}
+
+/// Respond with label sets from the most recent update for each server as JSON, optionally
+/// grouped by some labels, sorted, and limited to the largest label sets.
+pub async fn summary_handler(State(state): State<Arc<RequestState>>, Query(params): Query<SummaryParams>) -> Response {
+    let sort = match params.sort.as_deref().map(str::parse).transpose() {
+        Ok(s) => s.unwrap_or_default(),
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e }))).into_response(),
+    };
+
+    let query = SummaryQuery {
+        group_by: params.group_by.map(|g| {
+            g.split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(String::from)
+                .collect()
+        }),
+        sort,
+        limit: params.limit,
+    };
+
+    Json(Summary::new(&state.metrics.cycles(), &query)).into_response()
+}
+
+/// Respond with sampled keys of every label set matching all labels given as query
+/// parameters, e.g. `?thing=cart&user=user-1`, as JSON. Responds with `404` when key
+/// samples are not enabled in configuration.
+pub async fn keys_handler(
+    State(state): State<Arc<RequestState>>,
+    Query(filter): Query<BTreeMap<String, String>>,
+) -> Response {
+    if state.updater.rules().key_samples.is_none() {
+        let body = Json(serde_json::json!({
+            "error": "key samples are not enabled, set key_samples in configuration",
+        }));
+        return (StatusCode::NOT_FOUND, body).into_response();
+    }
+
+    let label_sets = summary::sampled_keys(&state.metrics.cycles(), &filter);
+    Json(KeysResponse { label_sets }).into_response()
+}
+
+/// Respond with the number and total size of keys that no rule matched for each server as
+/// JSON, along with example keys when key samples are enabled in configuration.
+pub async fn unmatched_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
+    let servers = summary::unmatched_keys(&state.metrics.cycles());
+    Json(UnmatchedResponse { servers })
+}
+
 pub async fn pprof_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
 
@@ -48,3 +269,22 @@ pub async fn pprof_handler(State(state): State<Arc<RequestState>>) -> impl IntoR
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::RateLimit;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(Duration::from_secs(60));
+        assert!(limit.try_acquire().is_ok());
+
+        let wait = limit.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+
+        let limit = RateLimit::new(Duration::ZERO);
+        assert!(limit.try_acquire().is_ok());
+        assert!(limit.try_acquire().is_ok());
+    }
+}
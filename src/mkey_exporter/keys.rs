@@ -1,17 +1,218 @@
-use crate::config::RuleGroup;
+use crate::config::{Action, Rule, RuleGroup};
 use mtop_client::Meta;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Result of applying rules to a single key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extraction {
+    /// Labels for the key or `None` if the key should be dropped.
+    pub labels: Option<Vec<(String, String)>>,
+    /// True if the key wasn't dropped by a drop rule and no rule set a label for it. Keys
+    /// that are dropped because unmatched keys are dropped are still unmatched.
+    pub unmatched: bool,
+}
+
+/// Only one in this many keys is timed when collecting rule stats, and the time taken is
+/// scaled up to estimate the time for every key. Reading the clock for every rule and every
+/// key would make collecting stats noticeably slower.
+const TIME_SAMPLE_INTERVAL: u32 = 64;
+
+/// Number of keys each rule matched and the time spent matching them, indexed by the
+/// position of the rule in configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    /// Keys the rule matched and was used for.
+    pub matches: Vec<u64>,
+    /// Keys the rule matched but was skipped for because an earlier rule already set the
+    /// same label.
+    pub shadowed: Vec<u64>,
+    /// Time spent checking keys against the rule, not including checks only done to find
+    /// shadowed keys. Estimated from a sample of keys.
+    pub time: Vec<Duration>,
+    // Number of keys rules have been applied to, used to pick keys to time.
+    keys: u32,
+}
+
+impl RuleStats {
+    pub fn new(num_rules: usize) -> Self {
+        Self {
+            matches: vec![0; num_rules],
+            shadowed: vec![0; num_rules],
+            time: vec![Duration::ZERO; num_rules],
+            keys: 0,
+        }
+    }
+
+    /// Add stats from applying the same rules to other keys.
+    pub fn merge(&mut self, other: &RuleStats) {
+        for (s, o) in self.matches.iter_mut().zip(other.matches.iter()) {
+            *s += *o;
+        }
+
+        for (s, o) in self.shadowed.iter_mut().zip(other.shadowed.iter()) {
+            *s += *o;
+        }
+
+        for (s, o) in self.time.iter_mut().zip(other.time.iter()) {
+            *s += *o;
+        }
+    }
+
+    /// Scale match counts up to estimate them for all keys when only a sample of keys was
+    /// processed. Time spent is not scaled since it's the time actually taken.
+    pub fn scale(&mut self, factor: f64) {
+        for v in self.matches.iter_mut().chain(self.shadowed.iter_mut()) {
+            *v = (*v as f64 * factor).round() as u64;
+        }
+    }
+
+    /// Count a key that rules are about to be applied to, returning true if the time taken
+    /// by each rule should be measured for it.
+    fn next_key_timed(stats: &mut Option<&mut RuleStats>) -> bool {
+        match stats {
+            Some(s) => {
+                let timed = s.keys == 0;
+                s.keys = (s.keys + 1) % TIME_SAMPLE_INTERVAL;
+                timed
+            }
+            None => false,
+        }
+    }
+
+    fn timed<T, F>(stats: &mut Option<&mut RuleStats>, timed: bool, index: usize, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        match stats {
+            Some(s) if timed => {
+                let start = Instant::now();
+                let res = f();
+                s.time[index] += start.elapsed() * TIME_SAMPLE_INTERVAL;
+                res
+            }
+            _ => f(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct LabelParser<'a> {
     config: &'a RuleGroup,
+    // Drop rules along with their position in configuration.
+    drops: Vec<(usize, &'a Rule)>,
+    // Rules that set labels along with their position in configuration and the position of
+    // the label within the declared labels. Label positions are only used when labels are
+    // declared in the configuration.
+    rules: Vec<(usize, &'a Rule, Option<usize>)>,
 }
 
 impl<'a> LabelParser<'a> {
     pub fn new(config: &'a RuleGroup) -> Self {
-        Self { config }
+        let drops = config
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.action == Action::Drop)
+            .collect();
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.action == Action::Label)
+            .map(|(i, r)| (i, r, config.labels.iter().position(|l| l.name == r.label_name)))
+            .collect();
+
+        Self { config, drops, rules }
+    }
+
+    /// Get labels for the key of `meta` based on configured rules or `None` if the key
+    /// should be dropped because it matches a drop rule (or doesn't match any rules when
+    /// unmatched keys are dropped). If labels are declared in the configuration, the result
+    /// always contains every declared label in the order declared, using default values for
+    /// labels that no rule set.
+    pub fn extract(&self, meta: &Meta) -> Option<Vec<(String, String)>> {
+        self.apply(meta, None).labels
+    }
+
+    /// Get labels for the key of `meta` the same way as `extract`, along with whether any
+    /// rule matched it. When `stats` is given, the number of keys matched and shadowed by
+    /// each rule are added to it, along with the time taken by each rule for a sample of
+    /// keys. Finding shadowed keys requires checking rules that would otherwise be skipped,
+    /// so this is slower when stats are collected.
+    pub fn apply(&self, meta: &Meta, mut stats: Option<&mut RuleStats>) -> Extraction {
+        let timed = RuleStats::next_key_timed(&mut stats);
+        for (i, rule) in self.drops.iter() {
+            if RuleStats::timed(&mut stats, timed, *i, || rule.pattern.is_match(&meta.key)) {
+                record_match(&mut stats, *i);
+                return Extraction {
+                    labels: None,
+                    unmatched: false,
+                };
+            }
+        }
+
+        let (labels, matched) = if self.config.labels.is_empty() {
+            self.extract_undeclared(meta, stats, timed)
+        } else {
+            self.extract_declared(meta, stats, timed)
+        };
+
+        Extraction {
+            labels: if !matched && self.config.drop_unmatched {
+                None
+            } else {
+                Some(labels)
+            },
+            unmatched: !matched,
+        }
+    }
+
+    fn extract_declared(
+        &self,
+        meta: &Meta,
+        mut stats: Option<&mut RuleStats>,
+        timed: bool,
+    ) -> (Vec<(String, String)>, bool) {
+        let mut labels: Vec<(String, String)> = self
+            .config
+            .labels
+            .iter()
+            .map(|l| (l.name.clone(), l.default.clone()))
+            .collect();
+        let mut set = vec![false; labels.len()];
+        let mut matched = false;
+
+        for (i, rule, pos) in self.rules.iter() {
+            // Rules for labels that aren't declared are rejected during validation
+            let pos = match pos {
+                Some(p) if !set[*p] => *p,
+                _ => {
+                    record_shadowed(&mut stats, *i, rule, &meta.key);
+                    continue;
+                }
+            };
+
+            if let Some(c) = RuleStats::timed(&mut stats, timed, *i, || rule.pattern.captures(&meta.key)) {
+                record_match(&mut stats, *i);
+                set[pos] = true;
+                matched = true;
+
+                let value = &mut labels[pos].1;
+                value.clear();
+                c.expand(&rule.label_value, value);
+            }
+        }
+
+        (labels, matched)
     }
 
-    pub fn extract(&self, meta: &Meta) -> Vec<(String, String)> {
+    fn extract_undeclared(
+        &self,
+        meta: &Meta,
+        mut stats: Option<&mut RuleStats>,
+        timed: bool,
+    ) -> (Vec<(String, String)>, bool) {
         // Using a Vec here instead of a HashSet because checking for inclusion
         // in a vector is faster when the number of entries is small. The number
         // of label names should be small since the correspond to labels added to
@@ -19,12 +220,14 @@ impl<'a> LabelParser<'a> {
         let mut names = Vec::new();
         let mut labels = Vec::new();
         let mut value = String::new();
-        for rule in self.config.rules.iter() {
+        for (i, rule, _) in self.rules.iter() {
             if names.contains(&&rule.label_name) {
+                record_shadowed(&mut stats, *i, rule, &meta.key);
                 continue;
             }
 
-            if let Some(c) = rule.pattern.captures(&meta.key) {
+            if let Some(c) = RuleStats::timed(&mut stats, timed, *i, || rule.pattern.captures(&meta.key)) {
+                record_match(&mut stats, *i);
                 names.push(&rule.label_name);
 
                 value.clear();
@@ -33,14 +236,127 @@ impl<'a> LabelParser<'a> {
             }
         }
 
-        labels
+        let matched = !labels.is_empty();
+        (labels, matched)
+    }
+
+    /// Apply every rule to `key` the same way `extract` does, recording whether each rule
+    /// matched, the capture groups it found, the expanded label value, and if the rule was
+    /// skipped because an earlier rule already set its label. Unlike `extract`, rules are
+    /// evaluated even when an earlier rule has already set their label.
+    pub fn explain(&self, key: &str) -> Vec<RuleExplanation> {
+        let mut set_by: Vec<(&String, usize)> = Vec::new();
+        let mut out = Vec::with_capacity(self.config.rules.len());
+
+        for (i, rule) in self.config.rules.iter().enumerate() {
+            let skipped_by = if rule.action == Action::Label {
+                set_by
+                    .iter()
+                    .find(|(name, _)| *name == &rule.label_name)
+                    .map(|(_, idx)| *idx)
+            } else {
+                None
+            };
+
+            let mut explanation = RuleExplanation {
+                index: i,
+                action: rule.action,
+                label_name: rule.label_name.clone(),
+                matched: false,
+                captures: Vec::new(),
+                value: None,
+                skipped_by,
+            };
+
+            if let Some(c) = rule.pattern.captures(key) {
+                for (group, name) in rule.pattern.capture_names().enumerate() {
+                    if let Some(m) = c.get(group) {
+                        let group = name.map(|n| n.to_owned()).unwrap_or_else(|| group.to_string());
+                        explanation.captures.push((group, m.as_str().to_owned()));
+                    }
+                }
+
+                explanation.matched = true;
+                if rule.action == Action::Label {
+                    if skipped_by.is_none() {
+                        set_by.push((&rule.label_name, i));
+                    }
+
+                    let mut value = String::new();
+                    c.expand(&rule.label_value, &mut value);
+                    explanation.value = Some(value);
+                }
+            }
+
+            out.push(explanation);
+        }
+
+        out
+    }
+}
+
+fn record_match(stats: &mut Option<&mut RuleStats>, index: usize) {
+    if let Some(s) = stats {
+        s.matches[index] += 1;
+    }
+}
+
+fn record_shadowed(stats: &mut Option<&mut RuleStats>, index: usize, rule: &Rule, key: &str) {
+    if let Some(s) = stats {
+        if rule.pattern.is_match(key) {
+            s.shadowed[index] += 1;
+        }
+    }
+}
+
+/// Result of applying a single rule to a key, produced by `LabelParser::explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleExplanation {
+    pub index: usize,
+    pub action: Action,
+    pub label_name: String,
+    pub matched: bool,
+    pub captures: Vec<(String, String)>,
+    pub value: Option<String>,
+    pub skipped_by: Option<usize>,
+}
+
+impl Display for RuleExplanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.action {
+            Action::Label => write!(f, "rules[{}] {}: ", self.index, self.label_name)?,
+            Action::Drop => write!(f, "rules[{}] drop: ", self.index)?,
+        }
+
+        if self.matched {
+            write!(f, "matched, captures")?;
+            for (group, value) in self.captures.iter() {
+                write!(f, " {}={:?}", group, value)?;
+            }
+
+            if let Some(v) = &self.value {
+                write!(f, ", value {:?}", v)?;
+            }
+
+            if self.action == Action::Drop {
+                write!(f, ", key dropped")?;
+            }
+        } else {
+            write!(f, "no match")?;
+        }
+
+        if let Some(i) = self.skipped_by {
+            write!(f, ", skipped: label already set by rules[{}]", i)?;
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::LabelParser;
-    use crate::config::{Rule, RuleGroup, RulePattern};
+    use super::{LabelParser, RuleExplanation, RuleStats, TIME_SAMPLE_INTERVAL};
+    use crate::config::{Action, Label, Rule, RuleGroup, RulePattern};
     use mtop_client::Meta;
 
     fn new_meta(key: &str) -> Meta {
@@ -53,6 +369,7 @@ mod test {
     fn user_rule() -> Rule {
         Rule {
             pattern: RulePattern::new(r"\w+:([\w-]+):").unwrap(),
+            action: Action::Label,
             label_name: "user".to_owned(),
             label_value: "u$1".to_owned(),
         }
@@ -61,6 +378,7 @@ mod test {
     fn type_rule() -> Rule {
         Rule {
             pattern: RulePattern::new(r"([\w-]+):\w+:").unwrap(),
+            action: Action::Label,
             label_name: "type".to_owned(),
             label_value: "$1".to_owned(),
         }
@@ -70,16 +388,19 @@ mod test {
         vec![
             Rule {
                 pattern: RulePattern::new(r"u-c:\w+:").unwrap(),
+                action: Action::Label,
                 label_name: "type".to_owned(),
                 label_value: "cart".to_owned(),
             },
             Rule {
                 pattern: RulePattern::new(r"u-p:\w+:").unwrap(),
+                action: Action::Label,
                 label_name: "type".to_owned(),
                 label_value: "profile".to_owned(),
             },
             Rule {
                 pattern: RulePattern::new(r"([\w-]+):\w+:").unwrap(),
+                action: Action::Label,
                 label_name: "type".to_owned(),
                 label_value: "unknown".to_owned(),
             },
@@ -92,10 +413,11 @@ mod test {
         let group = RuleGroup {
             name: "test".to_owned(),
             rules: vec![user_rule()],
+            ..Default::default()
         };
 
         let parser = LabelParser::new(&group);
-        let labels = parser.extract(&meta);
+        let labels = parser.extract(&meta).unwrap();
 
         assert_eq!(vec![("user".to_owned(), "u12345".to_owned())], labels);
     }
@@ -106,10 +428,11 @@ mod test {
         let group = RuleGroup {
             name: "test".to_owned(),
             rules: vec![user_rule(), type_rule()],
+            ..Default::default()
         };
 
         let parser = LabelParser::new(&group);
-        let labels = parser.extract(&meta);
+        let labels = parser.extract(&meta).unwrap();
 
         assert_eq!(
             vec![
@@ -133,12 +456,13 @@ mod test {
         let group = RuleGroup {
             name: "test".to_owned(),
             rules,
+            ..Default::default()
         };
 
         let parser = LabelParser::new(&group);
-        let labels1 = parser.extract(&meta1);
-        let labels2 = parser.extract(&meta2);
-        let labels3 = parser.extract(&meta3);
+        let labels1 = parser.extract(&meta1).unwrap();
+        let labels2 = parser.extract(&meta2).unwrap();
+        let labels3 = parser.extract(&meta3).unwrap();
 
         assert_eq!(
             vec![
@@ -162,4 +486,224 @@ mod test {
             labels3
         );
     }
+
+    #[test]
+    fn test_extract_declared_labels() {
+        let meta1 = new_meta("u-p:123:something");
+        let meta2 = new_meta("something");
+
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            labels: vec![
+                Label {
+                    name: "type".to_owned(),
+                    default: "none".to_owned(),
+                },
+                Label {
+                    name: "user".to_owned(),
+                    default: "unknown".to_owned(),
+                },
+            ],
+            rules: vec![user_rule(), type_rule()],
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+        let labels1 = parser.extract(&meta1).unwrap();
+        let labels2 = parser.extract(&meta2).unwrap();
+
+        assert_eq!(
+            vec![
+                ("type".to_owned(), "u-p".to_owned()),
+                ("user".to_owned(), "u123".to_owned()),
+            ],
+            labels1
+        );
+        assert_eq!(
+            vec![
+                ("type".to_owned(), "none".to_owned()),
+                ("user".to_owned(), "unknown".to_owned()),
+            ],
+            labels2
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut rules = Vec::new();
+        rules.push(user_rule());
+        rules.extend(specific_type_rules());
+
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            rules,
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+        let explanations = parser.explain("u-c:456:something");
+
+        assert_eq!(
+            vec![
+                RuleExplanation {
+                    index: 0,
+                    action: Action::Label,
+                    label_name: "user".to_owned(),
+                    matched: true,
+                    captures: vec![
+                        ("0".to_owned(), "c:456:".to_owned()),
+                        ("1".to_owned(), "456".to_owned())
+                    ],
+                    value: Some("u456".to_owned()),
+                    skipped_by: None,
+                },
+                RuleExplanation {
+                    index: 1,
+                    action: Action::Label,
+                    label_name: "type".to_owned(),
+                    matched: true,
+                    captures: vec![("0".to_owned(), "u-c:456:".to_owned())],
+                    value: Some("cart".to_owned()),
+                    skipped_by: None,
+                },
+                RuleExplanation {
+                    index: 2,
+                    action: Action::Label,
+                    label_name: "type".to_owned(),
+                    matched: false,
+                    captures: vec![],
+                    value: None,
+                    skipped_by: Some(1),
+                },
+                RuleExplanation {
+                    index: 3,
+                    action: Action::Label,
+                    label_name: "type".to_owned(),
+                    matched: true,
+                    captures: vec![
+                        ("0".to_owned(), "u-c:456:".to_owned()),
+                        ("1".to_owned(), "u-c".to_owned())
+                    ],
+                    value: Some("unknown".to_owned()),
+                    skipped_by: Some(1),
+                },
+            ],
+            explanations
+        );
+    }
+
+    #[test]
+    fn test_extract_drop_rule() {
+        let meta1 = new_meta("u-p:123:something");
+        let meta2 = new_meta("lock:123:something");
+
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            rules: vec![
+                user_rule(),
+                Rule {
+                    pattern: RulePattern::new(r"^lock:").unwrap(),
+                    action: Action::Drop,
+                    label_name: "".to_owned(),
+                    label_value: "".to_owned(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+
+        assert_eq!(
+            Some(vec![("user".to_owned(), "u123".to_owned())]),
+            parser.extract(&meta1)
+        );
+        assert_eq!(None, parser.extract(&meta2));
+    }
+
+    #[test]
+    fn test_extract_drop_unmatched() {
+        let meta1 = new_meta("u-p:123:something");
+        let meta2 = new_meta("something");
+
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            labels: vec![Label {
+                name: "user".to_owned(),
+                default: "unknown".to_owned(),
+            }],
+            rules: vec![user_rule()],
+            drop_unmatched: true,
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+
+        assert_eq!(
+            Some(vec![("user".to_owned(), "u123".to_owned())]),
+            parser.extract(&meta1)
+        );
+        assert_eq!(None, parser.extract(&meta2));
+    }
+
+    #[test]
+    fn test_apply_rule_stats() {
+        let mut rules = vec![Rule {
+            pattern: RulePattern::new(r"^lock:").unwrap(),
+            action: Action::Drop,
+            label_name: "".to_owned(),
+            label_value: "".to_owned(),
+        }];
+        rules.extend(specific_type_rules());
+
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            rules,
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+        let mut stats = RuleStats::new(group.rules.len());
+        for key in [
+            "u-p:123:something",
+            "u-c:456:something",
+            "u-v:789:something",
+            "lock:123:x",
+        ] {
+            assert!(!parser.apply(&new_meta(key), Some(&mut stats)).unmatched);
+        }
+
+        // Every key with a type also matches the last, more general, rule
+        assert_eq!(vec![1, 1, 1, 1], stats.matches);
+        assert_eq!(vec![0, 0, 0, 2], stats.shadowed);
+    }
+
+    #[test]
+    fn test_rule_stats_time_sampled() {
+        let mut stats = RuleStats::new(1);
+        let mut opt = Some(&mut stats);
+        let timed = (0..TIME_SAMPLE_INTERVAL * 2)
+            .filter(|_| RuleStats::next_key_timed(&mut opt))
+            .count();
+        assert_eq!(2, timed);
+        assert!(!RuleStats::next_key_timed(&mut None));
+    }
+
+    #[test]
+    fn test_apply_unmatched() {
+        let group = RuleGroup {
+            name: "test".to_owned(),
+            rules: vec![user_rule()],
+            drop_unmatched: true,
+            ..Default::default()
+        };
+
+        let parser = LabelParser::new(&group);
+        let res1 = parser.apply(&new_meta("u-p:123:something"), None);
+        let res2 = parser.apply(&new_meta("something"), None);
+
+        assert!(!res1.unmatched);
+        assert!(res1.labels.is_some());
+        assert!(res2.unmatched);
+        assert_eq!(None, res2.labels);
+    }
 }
@@ -1,5 +1,18 @@
+pub mod aggregate;
+pub mod backoff;
 pub mod config;
+pub mod crawler;
 pub mod http;
 pub mod keys;
+pub mod limits;
 pub mod metrics;
+pub mod pipeline;
 pub mod profile;
+pub mod report;
+pub mod sample;
+pub mod snapshot;
+pub mod source;
+pub mod status;
+pub mod summary;
+pub mod testing;
+pub mod updater;
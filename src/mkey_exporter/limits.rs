@@ -0,0 +1,475 @@
+use crate::aggregate::{Aggregates, LabelCounts};
+use crate::config::Limits;
+use crate::metrics::{self, SERVER_LABEL};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+/// Number of label values and label sets combined into overflow series by a `Limiter`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LimitStats {
+    /// Number of distinct values replaced by the overflow value for each label with
+    /// a `max_values` limit, including labels where no values were replaced.
+    pub values_dropped: Vec<(String, usize)>,
+    /// Number of label sets combined into a single overflow label set.
+    pub series_dropped: usize,
+}
+
+/// Enforce cardinality limits on aggregated label sets before they are turned into series.
+#[derive(Debug)]
+pub struct Limiter<'a> {
+    config: &'a Limits,
+}
+
+impl<'a> Limiter<'a> {
+    pub fn new(config: &'a Limits) -> Self {
+        Self { config }
+    }
+
+    /// Apply per-label `max_values` limits and then the `max_series` limit to `aggregates`
+    /// from a single server, combining anything beyond the limits into overflow series.
+    pub fn apply(&self, mut aggregates: Aggregates) -> (Aggregates, LimitStats) {
+        let mut stats = LimitStats::default();
+
+        for (name, max) in self.config.max_values.iter() {
+            let (limited, dropped) = self.limit_values(aggregates, name, *max);
+            aggregates = limited;
+            stats.values_dropped.push((name.clone(), dropped.len()));
+        }
+
+        if let Some(max) = self.config.max_series {
+            let (limited, dropped) = self.limit_series(aggregates, max);
+            aggregates = limited;
+            stats.series_dropped = dropped;
+        }
+
+        (aggregates, stats)
+    }
+
+    /// Apply per-label `max_values` limits and then the `max_series` limit to label sets
+    /// from every server combined so that they limit the total number of values and series,
+    /// not the number for each server. Values are ranked by their total from all servers.
+    /// Label sets beyond `max_series` are combined into one overflow label set per server
+    /// that keeps the server label and uses the overflow value for every other label, so at
+    /// least one series is kept for each server with label sets beyond the limit.
+    ///
+    /// Returns `None` if there are no limits. Otherwise, returns the remaining label sets,
+    /// each including the server label, and the number of values and label sets from each
+    /// server that were combined into overflow series.
+    pub fn apply_servers(&self, servers: &[(&str, &Aggregates)]) -> Option<(Aggregates, HashMap<String, LimitStats>)> {
+        if self.config.max_values.is_empty() && self.config.max_series.is_none() {
+            return None;
+        }
+
+        let total: usize = servers.iter().map(|(_, a)| a.len()).sum();
+        let mut combined = Aggregates::with_capacity(total);
+        let mut stats: HashMap<String, LimitStats> = HashMap::with_capacity(servers.len());
+        for (server, aggregates) in servers {
+            stats.insert(server.to_string(), LimitStats::default());
+            for (labels, counts) in aggregates.iter() {
+                combined.insert(metrics::with_server(server, labels), counts.clone());
+            }
+        }
+
+        for (name, max) in self.config.max_values.iter() {
+            // Distinct values of the label for each server, to count how many values from
+            // each server were replaced once values are ranked across all servers.
+            let mut values: HashSet<(String, String)> = HashSet::new();
+            for labels in combined.keys() {
+                if let Some((_, v)) = labels.iter().find(|(n, _)| n == name) {
+                    values.insert((server_of(labels), v.clone()));
+                }
+            }
+
+            let (limited, dropped) = self.limit_values(combined, name, *max);
+            combined = limited;
+
+            for s in stats.values_mut() {
+                s.values_dropped.push((name.clone(), 0));
+            }
+
+            for (server, _) in values.iter().filter(|(_, v)| dropped.contains(v)) {
+                if let Some((_, n)) = stats.get_mut(server).and_then(|s| s.values_dropped.last_mut()) {
+                    *n += 1;
+                }
+            }
+        }
+
+        if let Some(max) = self.config.max_series {
+            let (limited, dropped) = self.limit_series_servers(combined, max);
+            combined = limited;
+
+            for (server, n) in dropped {
+                if let Some(s) = stats.get_mut(&server) {
+                    s.series_dropped = n;
+                }
+            }
+        }
+
+        Some((combined, stats))
+    }
+
+    /// Keep the `max` values of the label `name` with the highest rank and replace all
+    /// others with the overflow value, merging label sets that become identical. The
+    /// overflow value itself is always kept. Returns the remaining label sets and the
+    /// values that were replaced.
+    fn limit_values(&self, aggregates: Aggregates, name: &str, max: usize) -> (Aggregates, HashSet<String>) {
+        let mut totals: HashMap<&str, i64> = HashMap::new();
+        for (labels, counts) in aggregates.iter() {
+            if let Some((_, v)) = labels.iter().find(|(n, _)| n == name) {
+                if *v != self.config.overflow_value {
+                    *totals.entry(v.as_str()).or_default() += counts.rank(self.config.rank_by);
+                }
+            }
+        }
+
+        if totals.len() <= max {
+            return (aggregates, HashSet::new());
+        }
+
+        let mut ranked: Vec<(&str, i64)> = totals.into_iter().collect();
+        ranked.sort_unstable_by_key(|(v, total)| (Reverse(*total), *v));
+
+        let dropped: HashSet<String> = ranked.drain(max..).map(|(v, _)| v.to_owned()).collect();
+        let mut out = Aggregates::with_capacity(aggregates.len());
+
+        for (mut labels, counts) in aggregates {
+            if let Some((_, v)) = labels.iter_mut().find(|(n, _)| n == name) {
+                if dropped.contains(v) {
+                    v.clone_from(&self.config.overflow_value);
+                }
+            }
+
+            out.entry(labels).or_default().merge(&counts);
+        }
+
+        (out, dropped)
+    }
+
+    /// Keep the `max - 1` label sets with the highest rank and combine all others into
+    /// a single label set where every value is the overflow value. Returns the remaining
+    /// label sets and the number of label sets that were combined.
+    fn limit_series(&self, aggregates: Aggregates, max: usize) -> (Aggregates, usize) {
+        if aggregates.len() <= max {
+            return (aggregates, 0);
+        }
+
+        let mut ranked = self.rank_series(aggregates);
+        let keep = max - 1;
+        let dropped = ranked.len() - keep;
+        let mut overflow_labels: Vec<(String, String)> = Vec::new();
+        let mut overflow_counts = LabelCounts::default();
+
+        for (labels, counts) in ranked.drain(keep..) {
+            for (name, _) in labels.iter() {
+                if !overflow_labels.iter().any(|(n, _)| n == name) {
+                    overflow_labels.push((name.clone(), self.config.overflow_value.clone()));
+                }
+            }
+
+            overflow_counts.merge(&counts);
+        }
+
+        let mut out: Aggregates = ranked.into_iter().collect();
+        out.entry(overflow_labels).or_default().merge(&overflow_counts);
+        (out, dropped)
+    }
+
+    /// Keep the label sets with the highest rank and combine all others into one label set
+    /// for each server where every value except the server is the overflow value, using at
+    /// most `max` label sets in total when possible. Returns the remaining label sets and the
+    /// number of label sets from each server that were combined.
+    fn limit_series_servers(&self, aggregates: Aggregates, max: usize) -> (Aggregates, HashMap<String, usize>) {
+        if aggregates.len() <= max {
+            return (aggregates, HashMap::new());
+        }
+
+        let mut ranked = self.rank_series(aggregates);
+
+        // Every server with label sets beyond the limit needs its own overflow label set,
+        // so keep fewer label sets until the kept and overflow label sets fit the limit.
+        let mut keep = max;
+        let mut overflow_servers: HashSet<String> = ranked[keep..].iter().map(|(l, _)| server_of(l)).collect();
+        while keep > 0 && keep + overflow_servers.len() > max {
+            keep -= 1;
+            overflow_servers.insert(server_of(&ranked[keep].0));
+        }
+
+        let mut overflow: HashMap<String, (Vec<(String, String)>, LabelCounts)> = HashMap::new();
+        let mut dropped: HashMap<String, usize> = HashMap::new();
+
+        for (labels, counts) in ranked.drain(keep..) {
+            let server = server_of(&labels);
+            let (overflow_labels, overflow_counts) = overflow
+                .entry(server.clone())
+                .or_insert_with(|| (vec![(SERVER_LABEL.to_owned(), server.clone())], LabelCounts::default()));
+
+            for (name, _) in labels.iter() {
+                if !overflow_labels.iter().any(|(n, _)| n == name) {
+                    overflow_labels.push((name.clone(), self.config.overflow_value.clone()));
+                }
+            }
+
+            // Overflow label sets from limiting each server are merged into the overflow
+            // label set for the server without counting them again.
+            let is_overflow = labels
+                .iter()
+                .all(|(n, v)| n == SERVER_LABEL || *v == self.config.overflow_value);
+            if !is_overflow {
+                *dropped.entry(server).or_default() += 1;
+            }
+
+            overflow_counts.merge(&counts);
+        }
+
+        let mut out: Aggregates = ranked.into_iter().collect();
+        for (labels, counts) in overflow.into_values() {
+            out.entry(labels).or_default().merge(&counts);
+        }
+
+        (out, dropped)
+    }
+
+    /// Sort label sets by rank, highest first, breaking ties by the labels themselves so
+    /// the same label sets are kept every time.
+    fn rank_series(&self, aggregates: Aggregates) -> Vec<(Vec<(String, String)>, LabelCounts)> {
+        let mut ranked: Vec<(Vec<(String, String)>, LabelCounts)> = aggregates.into_iter().collect();
+        ranked.sort_unstable_by(|(l1, c1), (l2, c2)| {
+            let (r1, r2) = (c1.rank(self.config.rank_by), c2.rank(self.config.rank_by));
+            r2.cmp(&r1).then_with(|| l1.cmp(l2))
+        });
+        ranked
+    }
+}
+
+/// Value of the server label of a label set, or an empty string if there isn't one.
+fn server_of(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .find(|(n, _)| n == SERVER_LABEL)
+        .map(|(_, v)| v.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LimitStats, Limiter};
+    use crate::aggregate::{Aggregates, LabelCounts};
+    use crate::config::{Limits, RankBy};
+
+    fn labels(user: &str, kind: &str) -> Vec<(String, String)> {
+        vec![
+            ("user".to_owned(), user.to_owned()),
+            ("kind".to_owned(), kind.to_owned()),
+        ]
+    }
+
+    fn counts(count: i64, size: i64) -> LabelCounts {
+        LabelCounts {
+            count,
+            size,
+            ..Default::default()
+        }
+    }
+
+    fn new_aggregates() -> Aggregates {
+        let mut aggregates = Aggregates::new();
+        aggregates.insert(labels("a", "x"), counts(10, 100));
+        aggregates.insert(labels("b", "x"), counts(5, 5000));
+        aggregates.insert(labels("c", "x"), counts(1, 10));
+        aggregates.insert(labels("c", "y"), counts(1, 10));
+        aggregates
+    }
+
+    #[test]
+    fn test_apply_no_limits() {
+        let limits = Limits::default();
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter.apply(new_aggregates());
+
+        assert_eq!(new_aggregates(), out);
+        assert_eq!(0, stats.series_dropped);
+        assert!(stats.values_dropped.is_empty());
+    }
+
+    #[test]
+    fn test_apply_max_values_by_count() {
+        let mut limits = Limits::default();
+        limits.max_values.insert("user".to_owned(), 1);
+        limits.max_values.insert("kind".to_owned(), 2);
+
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter.apply(new_aggregates());
+
+        let mut expected = Aggregates::new();
+        expected.insert(labels("a", "x"), counts(10, 100));
+        expected.insert(labels("__other__", "x"), counts(6, 5010));
+        expected.insert(labels("__other__", "y"), counts(1, 10));
+
+        assert_eq!(expected, out);
+        assert_eq!(
+            vec![("kind".to_owned(), 0), ("user".to_owned(), 2)],
+            stats.values_dropped
+        );
+    }
+
+    #[test]
+    fn test_apply_max_values_by_size() {
+        let mut limits = Limits::default();
+        limits.max_values.insert("user".to_owned(), 1);
+        limits.rank_by = RankBy::Size;
+
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter.apply(new_aggregates());
+
+        let mut expected = Aggregates::new();
+        expected.insert(labels("b", "x"), counts(5, 5000));
+        expected.insert(labels("__other__", "x"), counts(11, 110));
+        expected.insert(labels("__other__", "y"), counts(1, 10));
+
+        assert_eq!(expected, out);
+        assert_eq!(vec![("user".to_owned(), 2)], stats.values_dropped);
+    }
+
+    #[test]
+    fn test_apply_max_series() {
+        let limits = Limits {
+            max_series: Some(2),
+            ..Default::default()
+        };
+
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter.apply(new_aggregates());
+
+        let mut expected = Aggregates::new();
+        expected.insert(labels("a", "x"), counts(10, 100));
+        expected.insert(labels("__other__", "__other__"), counts(7, 5020));
+
+        assert_eq!(expected, out);
+        assert_eq!(3, stats.series_dropped);
+    }
+
+    fn with_server(server: &str, user: &str, kind: &str) -> Vec<(String, String)> {
+        vec![
+            ("server".to_owned(), server.to_owned()),
+            ("user".to_owned(), user.to_owned()),
+            ("kind".to_owned(), kind.to_owned()),
+        ]
+    }
+
+    #[test]
+    fn test_apply_servers_no_limits() {
+        let a = new_aggregates();
+        assert!(Limiter::new(&Limits::default())
+            .apply_servers(&[("cache-a:11211", &a)])
+            .is_none());
+    }
+
+    #[test]
+    fn test_apply_servers_within_limit() {
+        let limits = Limits {
+            max_series: Some(8),
+            ..Default::default()
+        };
+
+        let (a, b) = (new_aggregates(), new_aggregates());
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter
+            .apply_servers(&[("cache-a:11211", &a), ("cache-b:11211", &b)])
+            .unwrap();
+
+        assert_eq!(8, out.len());
+        assert_eq!(Some(&counts(10, 100)), out.get(&with_server("cache-b:11211", "a", "x")));
+        assert_eq!(Some(&LimitStats::default()), stats.get("cache-a:11211"));
+        assert_eq!(Some(&LimitStats::default()), stats.get("cache-b:11211"));
+    }
+
+    #[test]
+    fn test_apply_servers_max_values() {
+        let mut limits = Limits::default();
+        limits.max_values.insert("user".to_owned(), 2);
+
+        // Each server is within the limit on its own but not combined.
+        let mut a = Aggregates::new();
+        a.insert(labels("a", "x"), counts(10, 100));
+        a.insert(labels("b", "x"), counts(5, 50));
+        let mut b = Aggregates::new();
+        b.insert(labels("c", "x"), counts(8, 80));
+        b.insert(labels("d", "x"), counts(1, 10));
+
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter
+            .apply_servers(&[("cache-a:11211", &a), ("cache-b:11211", &b)])
+            .unwrap();
+
+        let mut expected = Aggregates::new();
+        expected.insert(with_server("cache-a:11211", "a", "x"), counts(10, 100));
+        expected.insert(with_server("cache-a:11211", "__other__", "x"), counts(5, 50));
+        expected.insert(with_server("cache-b:11211", "c", "x"), counts(8, 80));
+        expected.insert(with_server("cache-b:11211", "__other__", "x"), counts(1, 10));
+
+        assert_eq!(expected, out);
+        assert_eq!(
+            vec![("user".to_owned(), 1)],
+            stats.get("cache-a:11211").unwrap().values_dropped
+        );
+        assert_eq!(
+            vec![("user".to_owned(), 1)],
+            stats.get("cache-b:11211").unwrap().values_dropped
+        );
+    }
+
+    #[test]
+    fn test_apply_servers_max_series() {
+        let limits = Limits {
+            max_series: Some(4),
+            ..Default::default()
+        };
+
+        let mut b = Aggregates::new();
+        b.insert(labels("d", "x"), counts(20, 10));
+        b.insert(labels("e", "x"), counts(2, 10));
+
+        let a = new_aggregates();
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter
+            .apply_servers(&[("cache-a:11211", &a), ("cache-b:11211", &b)])
+            .unwrap();
+
+        // Both servers need an overflow series so only two label sets can be kept.
+        let mut expected = Aggregates::new();
+        expected.insert(with_server("cache-b:11211", "d", "x"), counts(20, 10));
+        expected.insert(with_server("cache-a:11211", "a", "x"), counts(10, 100));
+        expected.insert(with_server("cache-a:11211", "__other__", "__other__"), counts(7, 5020));
+        expected.insert(with_server("cache-b:11211", "__other__", "__other__"), counts(2, 10));
+
+        assert_eq!(expected, out);
+        assert_eq!(3, stats.get("cache-a:11211").unwrap().series_dropped);
+        assert_eq!(1, stats.get("cache-b:11211").unwrap().series_dropped);
+    }
+
+    #[test]
+    fn test_apply_servers_max_values_then_series() {
+        let mut limits = Limits {
+            max_series: Some(3),
+            ..Default::default()
+        };
+        limits.max_values.insert("user".to_owned(), 1);
+
+        let (a, b) = (new_aggregates(), new_aggregates());
+        let limiter = Limiter::new(&limits);
+        let (out, stats) = limiter
+            .apply_servers(&[("cache-a:11211", &a), ("cache-b:11211", &b)])
+            .unwrap();
+
+        assert!(out.len() <= 3);
+        assert_eq!(Some(&counts(10, 100)), out.get(&with_server("cache-a:11211", "a", "x")));
+        assert_eq!(
+            vec![("user".to_owned(), 2)],
+            stats.get("cache-a:11211").unwrap().values_dropped
+        );
+        assert_eq!(
+            vec![("user".to_owned(), 2)],
+            stats.get("cache-b:11211").unwrap().values_dropped
+        );
+    }
+}
@@ -1,23 +1,60 @@
-use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
+use crate::aggregate::{Aggregates, Distribution, LabelCounts};
+use crate::config::{Action, Limits, RuleGroup};
+use crate::keys::RuleStats;
+use crate::limits::{LimitStats, Limiter};
+use crate::pipeline::{Aggregation, Workers};
+use prometheus_client::encoding::text;
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, EncodeMetric, LabelValueEncoder, MetricEncoder};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::metrics::{MetricType, TypedMetric};
 use prometheus_client::registry::{Registry, Unit};
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const DEFAULT_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0];
-const RESULT_SUCCESS: UpdateResultLabels = UpdateResultLabels {
-    result: UpdateResult::Success,
-};
-const RESULT_FAILURE: UpdateResultLabels = UpdateResultLabels {
-    result: UpdateResult::Failure,
-};
+
+/// Name of the label added to every key count and size series to indicate which
+/// Memcached server the keys were fetched from.
+pub const SERVER_LABEL: &str = "server";
+
+/// Value of the `label_name` label of rule series for rules that drop keys.
+pub const DROP_LABEL_NAME: &str = "__drop__";
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct UpdateResultLabels {
+    server: String,
     result: UpdateResult,
+    reason: UpdateReason,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ServerLabels {
+    server: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LabelNameLabels {
+    server: String,
+    label_name: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct WorkerLabels {
+    threads: usize,
+    shards: usize,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RuleLabels {
+    server: String,
+    rule_index: usize,
+    label_name: String,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -35,83 +72,687 @@ impl EncodeLabelValue for UpdateResult {
     }
 }
 
+/// Value of the `reason` label of updates, `none` for successful updates so that every
+/// series has a non-empty value.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum UpdateReason {
+    None,
+    Failure(FailureReason),
+}
+
+impl EncodeLabelValue for UpdateReason {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        match self {
+            UpdateReason::None => EncodeLabelValue::encode(&"none", encoder),
+            UpdateReason::Failure(r) => EncodeLabelValue::encode(r, encoder),
+        }
+    }
+}
+
+/// Why an update for a server failed.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum FailureReason {
+    /// Fetching keys returned an error.
+    Error,
+    /// Fetching keys took longer than the allowed time.
+    Timeout,
+}
+
+impl EncodeLabelValue for FailureReason {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        match self {
+            FailureReason::Error => EncodeLabelValue::encode(&"error", encoder),
+            FailureReason::Timeout => EncodeLabelValue::encode(&"timeout", encoder),
+        }
+    }
+}
+
+/// Histogram that is set to a distribution computed during each update instead of
+/// observing individual values.
+#[derive(Clone, Debug, Default)]
+struct DistributionHistogram {
+    inner: Arc<RwLock<Distribution>>,
+}
+
+impl DistributionHistogram {
+    fn set(&self, distribution: &Distribution) {
+        let mut inner = self.inner.write().unwrap();
+        inner.clone_from(distribution);
+    }
+}
+
+impl TypedMetric for DistributionHistogram {
+    const TYPE: MetricType = MetricType::Histogram;
+}
+
+impl EncodeMetric for DistributionHistogram {
+    fn encode(&self, mut encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        let inner = self.inner.read().unwrap();
+        encoder.encode_histogram::<()>(inner.sum, inner.count, &inner.buckets, None)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        Self::TYPE
+    }
+}
+
+/// Results of the most recent complete update for a single server.
+#[derive(Debug)]
+pub struct Cycle {
+    pub aggregation: Aggregation,
+    /// Time the update finished.
+    pub completed: SystemTime,
+    /// Time taken by the update, including any retries.
+    pub duration: Duration,
+}
+
+/// Metrics about the exporter itself along with the results of the most recent update for
+/// each server.
+///
+/// Results of each update are published as a whole and never modified afterwards. Series
+/// for key counts and sizes from every server are built, with limits applied, each time an
+/// update is published and replaced as a whole. Every scrape sees complete updates, never a
+/// mix of values from two different updates, and only has to encode the series.
 #[derive(Debug)]
 pub struct Metrics {
     updates: Family<UpdateResultLabels, Counter>,
-    duration: Histogram,
-    counts: Family<Vec<(String, String)>, Gauge<i64>>,
-    sizes: Family<Vec<(String, String)>, Gauge<i64>>,
+    retries: Family<ServerLabels, Counter>,
+    up: Family<ServerLabels, Gauge<i64>>,
+    rule_matches: Family<RuleLabels, Counter>,
+    rule_shadowed: Family<RuleLabels, Counter>,
+    rule_time: Family<RuleLabels, Counter<f64, AtomicU64>>,
+    duration: Family<WorkerLabels, Histogram, fn() -> Histogram>,
+    reload_success: Gauge<i64>,
+    reload_timestamp: Gauge<f64, AtomicU64>,
+    limits: RwLock<Limits>,
+    cycles: RwLock<BTreeMap<String, Arc<Cycle>>>,
+    series: RwLock<Arc<CycleMetrics>>,
+    rebuild: Mutex<()>,
 }
 
 impl Metrics {
-    pub fn new(reg: &mut Registry) -> Self {
-        let updates = Family::<UpdateResultLabels, Counter>::default();
-        let duration = Histogram::new(DEFAULT_BUCKETS.iter().copied());
-        let counts = Family::<Vec<(String, String)>, Gauge<i64>>::default();
-        let sizes = Family::<Vec<(String, String)>, Gauge<i64>>::default();
+    pub fn new() -> Self {
+        Self {
+            updates: Family::default(),
+            retries: Family::default(),
+            up: Family::default(),
+            rule_matches: Family::default(),
+            rule_shadowed: Family::default(),
+            rule_time: Family::default(),
+            duration: Family::new_with_constructor(|| Histogram::new(DEFAULT_BUCKETS.iter().copied())),
+            reload_success: Gauge::default(),
+            reload_timestamp: Gauge::default(),
+            limits: RwLock::new(Limits::default()),
+            cycles: RwLock::new(BTreeMap::new()),
+            series: RwLock::new(Arc::new(CycleMetrics::default())),
+            rebuild: Mutex::new(()),
+        }
+    }
 
+    /// Encode all metrics in the OpenMetrics text format, using the most recently
+    /// published update for each server.
+    pub fn encode(&self, buf: &mut String) -> Result<(), fmt::Error> {
+        let mut reg = Registry::default();
         reg.register(
             "mkey_updates",
             "How many update loops have been run by the result",
-            updates.clone(),
+            self.updates.clone(),
+        );
+        reg.register(
+            "mkey_up",
+            "Whether the last update for a server was successful",
+            self.up.clone(),
+        );
+        reg.register(
+            "mkey_update_retries",
+            "How many times fetching keys was retried after a failure within an update",
+            self.retries.clone(),
         );
         reg.register_with_unit(
             "mkey_updates_duration",
             "How long update loops take in seconds",
             Unit::Seconds,
-            duration.clone(),
+            self.duration.clone(),
+        );
+        reg.register(
+            "mkey_rule_matches",
+            "Keys each rule matched and was used for, estimated when sampling",
+            self.rule_matches.clone(),
+        );
+        reg.register(
+            "mkey_rule_shadowed",
+            "Keys each rule matched but was skipped for because an earlier rule set the same label, estimated when sampling",
+            self.rule_shadowed.clone(),
+        );
+        reg.register_with_unit(
+            "mkey_rule_match_duration",
+            "Time spent checking keys against each rule",
+            Unit::Seconds,
+            self.rule_time.clone(),
+        );
+        reg.register(
+            "mkey_config_reload_success",
+            "Whether the last attempt to load rule configuration was successful",
+            self.reload_success.clone(),
+        );
+        reg.register_with_unit(
+            "mkey_config_last_reload_timestamp",
+            "Time of the last successful load of rule configuration as a UNIX timestamp",
+            Unit::Seconds,
+            self.reload_timestamp.clone(),
         );
+
+        let series = self.series.read().unwrap().clone();
+        series.register(&mut reg);
+        text::encode(buf, &reg)
+    }
+
+    /// Encode only series built from the published results of updates in the OpenMetrics
+    /// text format, without any metrics about the exporter itself such as configuration
+    /// reloads or update attempts. Used for reports of keys fetched once.
+    pub fn encode_results(&self, buf: &mut String) -> Result<(), fmt::Error> {
+        let mut reg = Registry::default();
+        let series = self.series.read().unwrap().clone();
+        series.register(&mut reg);
+        text::encode(buf, &reg)
+    }
+
+    /// Use `limits` for the number of values and series from all servers combined. Limits are
+    /// applied to each server by updates and again to all servers when results are published.
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+        self.rebuild();
+    }
+
+    /// Replace the results of the previous update for `server` with `cycle`.
+    pub fn publish(&self, server: &str, cycle: Cycle) {
+        {
+            let mut cycles = self.cycles.write().unwrap();
+            cycles.insert(server.to_owned(), Arc::new(cycle));
+        }
+
+        self.rebuild();
+    }
+
+    /// Build series for key counts and sizes from the most recent update for each server,
+    /// with limits for all servers combined applied, and replace the previous series.
+    fn rebuild(&self) {
+        // Only one rebuild runs at a time so that series built from older results never
+        // replace series built from newer results. Scrapes and lookups of results aren't
+        // blocked while series are being built.
+        let _guard = self.rebuild.lock().unwrap();
+        let cycles = self.cycles();
+        let limits = self.limits.read().unwrap().clone();
+        let servers: Vec<(&str, &Aggregates)> = cycles
+            .iter()
+            .map(|(s, c)| (s.as_str(), &c.aggregation.aggregates))
+            .collect();
+
+        let cycle_metrics = CycleMetrics::default();
+        match Limiter::new(&limits).apply_servers(&servers) {
+            Some((aggregates, mut stats)) => {
+                for (labels, counts) in aggregates {
+                    cycle_metrics.add_series(labels, &counts);
+                }
+
+                for (server, cycle) in cycles.iter() {
+                    cycle_metrics.add_totals(server, cycle, &stats.remove(server).unwrap_or_default());
+                }
+            }
+            None => {
+                for (server, cycle) in cycles.iter() {
+                    for (labels, counts) in cycle.aggregation.aggregates.iter() {
+                        cycle_metrics.add_series(with_server(server, labels), counts);
+                    }
+
+                    cycle_metrics.add_totals(server, cycle, &LimitStats::default());
+                }
+            }
+        }
+
+        *self.series.write().unwrap() = Arc::new(cycle_metrics);
+    }
+
+    /// Get the results of the most recent update for `server`, if any update has succeeded.
+    pub fn cycle(&self, server: &str) -> Option<Arc<Cycle>> {
+        let cycles = self.cycles.read().unwrap();
+        cycles.get(server).cloned()
+    }
+
+    /// Get the results of the most recent update for each server, sorted by server.
+    pub fn cycles(&self) -> Vec<(String, Arc<Cycle>)> {
+        // Only hold the lock long enough to copy references to each cycle.
+        let cycles = self.cycles.read().unwrap();
+        cycles.iter().map(|(s, c)| (s.clone(), c.clone())).collect()
+    }
+
+    pub fn reload_failure(&self) {
+        self.reload_success.set(0);
+    }
+
+    /// Record a successful load of rule configuration. Rule series are cleared since each
+    /// rule is identified by its position, which may now refer to a different rule.
+    pub fn reload_success(&self) {
+        self.reload_success.set(1);
+        self.reload_timestamp.set(unix_timestamp(SystemTime::now()));
+        self.rule_matches.clear();
+        self.rule_shadowed.clear();
+        self.rule_time.clear();
+    }
+
+    /// Start reporting a server as down until the first update for it succeeds.
+    pub fn add_server(&self, server: &str) {
+        self.up.get_or_create(&server_labels(server)).set(0);
+    }
+
+    pub fn incr_failure(&self, server: &str, reason: FailureReason) {
+        self.up.get_or_create(&server_labels(server)).set(0);
+        self.updates
+            .get_or_create(&UpdateResultLabels {
+                server: server.to_owned(),
+                result: UpdateResult::Failure,
+                reason: UpdateReason::Failure(reason),
+            })
+            .inc();
+    }
+
+    pub fn incr_retry(&self, server: &str) {
+        self.retries.get_or_create(&server_labels(server)).inc();
+    }
+
+    pub fn incr_rules(&self, server: &str, cfg: &RuleGroup, stats: &RuleStats) {
+        for (i, rule) in cfg.rules.iter().enumerate().take(stats.matches.len()) {
+            let labels = RuleLabels {
+                server: server.to_owned(),
+                rule_index: i,
+                label_name: match rule.action {
+                    Action::Label => rule.label_name.clone(),
+                    Action::Drop => DROP_LABEL_NAME.to_owned(),
+                },
+            };
+
+            self.rule_matches.get_or_create(&labels).inc_by(stats.matches[i]);
+            self.rule_shadowed.get_or_create(&labels).inc_by(stats.shadowed[i]);
+            self.rule_time
+                .get_or_create(&labels)
+                .inc_by(stats.time[i].as_secs_f64());
+        }
+    }
+
+    pub fn incr_success(&self, server: &str, workers: Workers, duration: Duration) {
+        self.up.get_or_create(&server_labels(server)).set(1);
+        self.duration
+            .get_or_create(&WorkerLabels {
+                threads: workers.threads,
+                shards: workers.shards,
+            })
+            .observe(duration.as_secs_f64());
+        self.updates
+            .get_or_create(&UpdateResultLabels {
+                server: server.to_owned(),
+                result: UpdateResult::Success,
+                reason: UpdateReason::None,
+            })
+            .inc();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Series built from the published results of the most recent update for each server.
+#[derive(Debug, Default, Clone)]
+struct CycleMetrics {
+    counts: Family<Vec<(String, String)>, Gauge<i64>>,
+    sizes: Family<Vec<(String, String)>, Gauge<i64>>,
+    ttls: Family<Vec<(String, String)>, DistributionHistogram>,
+    no_expiry: Family<Vec<(String, String)>, Gauge<i64>>,
+    expired: Family<Vec<(String, String)>, Gauge<i64>>,
+    item_sizes: Family<Vec<(String, String)>, DistributionHistogram>,
+    values_dropped: Family<LabelNameLabels, Gauge<i64>>,
+    series_dropped: Family<ServerLabels, Gauge<i64>>,
+    dropped_counts: Family<ServerLabels, Gauge<i64>>,
+    dropped_sizes: Family<ServerLabels, Gauge<i64>>,
+    unmatched_counts: Family<ServerLabels, Gauge<i64>>,
+    unmatched_sizes: Family<ServerLabels, Gauge<i64>>,
+    sample_rate: Family<ServerLabels, Gauge<f64, AtomicU64>>,
+    completed: Family<ServerLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl CycleMetrics {
+    /// Add totals for a single server that don't depend on label sets. `limited` is the
+    /// number of values and label sets from the server combined into overflow series due to
+    /// limits on all servers combined, in addition to those combined by the update.
+    fn add_totals(&self, server: &str, cycle: &Cycle, limited: &LimitStats) {
+        let aggregation = &cycle.aggregation;
+        for (name, dropped) in aggregation
+            .limits
+            .values_dropped
+            .iter()
+            .chain(limited.values_dropped.iter())
+        {
+            self.values_dropped
+                .get_or_create(&LabelNameLabels {
+                    server: server.to_owned(),
+                    label_name: name.clone(),
+                })
+                .inc_by(*dropped as i64);
+        }
+
+        let labels = ServerLabels {
+            server: server.to_owned(),
+        };
+
+        self.series_dropped
+            .get_or_create(&labels)
+            .set((aggregation.limits.series_dropped + limited.series_dropped) as i64);
+        self.dropped_counts
+            .get_or_create(&labels)
+            .set(aggregation.dropped.count);
+        self.dropped_sizes.get_or_create(&labels).set(aggregation.dropped.size);
+        self.unmatched_counts
+            .get_or_create(&labels)
+            .set(aggregation.unmatched.count);
+        self.unmatched_sizes
+            .get_or_create(&labels)
+            .set(aggregation.unmatched.size);
+        self.sample_rate.get_or_create(&labels).set(aggregation.sample_rate);
+        self.completed
+            .get_or_create(&labels)
+            .set(unix_timestamp(cycle.completed));
+    }
+
+    /// Add series for a single label set, which must include the server label.
+    fn add_series(&self, labels: Vec<(String, String)>, counts: &LabelCounts) {
+        self.counts.get_or_create(&labels).set(counts.count);
+        self.sizes.get_or_create(&labels).set(counts.size);
+
+        if let Some(ttl) = &counts.ttl {
+            self.ttls.get_or_create(&labels).set(&ttl.remaining);
+            self.no_expiry.get_or_create(&labels).set(ttl.no_expiry);
+            self.expired.get_or_create(&labels).set(ttl.expired);
+        }
+
+        if let Some(sizes) = &counts.item_sizes {
+            self.item_sizes.get_or_create(&labels).set(sizes);
+        }
+    }
+
+    /// Register clones of each family, which share series with this `CycleMetrics`.
+    fn register(&self, reg: &mut Registry) {
         reg.register(
             "mkey_memcached_counts",
             "Counts of keys matching the supplied configuration",
-            counts.clone(),
+            self.counts.clone(),
         );
         reg.register(
             "mkey_memcached_sizes",
             "Total size of all keys matching the supplied configuration",
-            sizes.clone(),
+            self.sizes.clone(),
+        );
+        reg.register_with_unit(
+            "mkey_memcached_ttl",
+            "Remaining time-to-live of keys matching the supplied configuration that have not expired",
+            Unit::Seconds,
+            self.ttls.clone(),
+        );
+        reg.register(
+            "mkey_memcached_no_expiry_counts",
+            "Counts of keys matching the supplied configuration that never expire",
+            self.no_expiry.clone(),
+        );
+        reg.register(
+            "mkey_memcached_expired_counts",
+            "Counts of keys matching the supplied configuration that have expired but not been reclaimed",
+            self.expired.clone(),
+        );
+        reg.register_with_unit(
+            "mkey_memcached_item_sizes",
+            "Sizes of individual keys matching the supplied configuration",
+            Unit::Bytes,
+            self.item_sizes.clone(),
+        );
+        reg.register(
+            "mkey_label_values_dropped",
+            "Distinct label values replaced with the overflow value due to max_values limits in the last update",
+            self.values_dropped.clone(),
+        );
+        reg.register(
+            "mkey_series_dropped",
+            "Label sets from a server combined into a single overflow series due to the max_series limit",
+            self.series_dropped.clone(),
+        );
+        reg.register(
+            "mkey_memcached_dropped_counts",
+            "Counts of keys dropped by rules in the last update",
+            self.dropped_counts.clone(),
+        );
+        reg.register(
+            "mkey_memcached_dropped_sizes",
+            "Total size of all keys dropped by rules in the last update",
+            self.dropped_sizes.clone(),
+        );
+        reg.register(
+            "mkey_unmatched_keys",
+            "Counts of keys no rule set a label for in the last update, including dropped keys",
+            self.unmatched_counts.clone(),
+        );
+        reg.register(
+            "mkey_unmatched_bytes",
+            "Total size of all keys no rule set a label for in the last update, including dropped keys",
+            self.unmatched_sizes.clone(),
+        );
+        reg.register(
+            "mkey_sample_rate",
+            "Fraction of keys processed in the last update, counts and sizes are estimates when less than 1",
+            self.sample_rate.clone(),
+        );
+        reg.register_with_unit(
+            "mkey_last_update_timestamp",
+            "Time the last successful update finished as a UNIX timestamp",
+            Unit::Seconds,
+            self.completed.clone(),
         );
+    }
+}
 
-        Self {
-            updates,
-            duration,
-            counts,
-            sizes,
-        }
+fn server_labels(server: &str) -> ServerLabels {
+    ServerLabels {
+        server: server.to_owned(),
     }
+}
+
+pub(crate) fn unix_timestamp(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Build the full set of labels for a series: the server label followed by any labels
+/// extracted from keys based on configured rules.
+pub(crate) fn with_server(server: &str, labels: &[(String, String)]) -> Vec<(String, String)> {
+    let mut out = Vec::with_capacity(labels.len() + 1);
+    out.push((SERVER_LABEL.to_owned(), server.to_owned()));
+    out.extend_from_slice(labels);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cycle, Metrics};
+    use crate::aggregate::LabelCounts;
+    use crate::config::{Limits, RuleGroup};
+    use crate::keys::RuleStats;
+    use crate::pipeline::Aggregation;
+    use std::time::{Duration, UNIX_EPOCH};
 
-    pub fn incr_failure(&self) {
-        self.updates.get_or_create(&RESULT_FAILURE).inc();
+    fn new_cycle(label: &str, count: i64) -> Cycle {
+        new_cycle_labels(&[(label, count)])
     }
 
-    pub fn incr_success(&self, duration: Duration) {
-        self.duration.observe(duration.as_secs_f64());
-        self.updates.get_or_create(&RESULT_SUCCESS).inc();
+    fn new_cycle_labels(things: &[(&str, i64)]) -> Cycle {
+        let mut aggregation = Aggregation::default();
+        for (label, count) in things {
+            aggregation.aggregates.insert(
+                vec![("thing".to_owned(), label.to_string())],
+                LabelCounts {
+                    count: *count,
+                    size: count * 10,
+                    ttl: None,
+                    item_sizes: None,
+                    keys: None,
+                },
+            );
+        }
+
+        Cycle {
+            aggregation,
+            completed: UNIX_EPOCH + Duration::from_secs(1700000000),
+            duration: Duration::from_secs(1),
+        }
     }
 
-    pub fn update_key(&self, labels: &Vec<(String, String)>, count: i64, size: i64) {
-        self.counts.get_or_create(labels).set(count);
-        self.sizes.get_or_create(labels).set(size);
+    #[test]
+    fn test_publish_replaces_previous() {
+        let metrics = Metrics::new();
+        metrics.publish("cache-a:11211", new_cycle("cart", 3));
+        metrics.publish("cache-a:11211", new_cycle("profile", 2));
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"profile\"} 2\n"));
+        assert!(!buf.contains("thing=\"cart\""));
+        assert!(buf.contains("mkey_last_update_timestamp_seconds{server=\"cache-a:11211\"} 1700000000.0\n"));
     }
 
-    pub fn cleanup_keys(&self, labels_to_remove: &HashSet<Vec<(String, String)>>) {
-        let mut counts_removed = 0;
-        let mut sizes_removed = 0;
+    #[test]
+    fn test_publish_multiple_servers() {
+        let metrics = Metrics::new();
+        metrics.publish("cache-a:11211", new_cycle("cart", 3));
+        metrics.publish("cache-b:11211", new_cycle("cart", 5));
 
-        for e in labels_to_remove.iter() {
-            if self.counts.remove(e) {
-                counts_removed += 1;
-            }
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
 
-            if self.sizes.remove(e) {
-                sizes_removed += 1;
-            }
-        }
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"cart\"} 3\n"));
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-b:11211\",thing=\"cart\"} 5\n"));
+        assert!(buf.contains("mkey_last_update_timestamp_seconds{server=\"cache-a:11211\"} 1700000000.0\n"));
+        assert!(buf.contains("mkey_last_update_timestamp_seconds{server=\"cache-b:11211\"} 1700000000.0\n"));
+    }
+
+    #[test]
+    fn test_rules_cleared_on_reload() {
+        let cfg: RuleGroup = serde_yaml::from_str(
+            r#"
+name: example
+rules:
+  - pattern: "^tmp:"
+    action: drop
+  - pattern: "^(\\w+):"
+    label_name: thing
+    label_value: "$1"
+"#,
+        )
+        .unwrap();
+
+        let mut stats = RuleStats::new(2);
+        stats.matches = vec![1, 4];
+        let metrics = Metrics::new();
+        metrics.incr_rules("cache-a:11211", &cfg, &stats);
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+        assert!(buf.contains(
+            "mkey_rule_matches_total{server=\"cache-a:11211\",rule_index=\"0\",label_name=\"__drop__\"} 1\n"
+        ));
+        assert!(
+            buf.contains("mkey_rule_matches_total{server=\"cache-a:11211\",rule_index=\"1\",label_name=\"thing\"} 4\n")
+        );
+
+        metrics.reload_success();
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+        assert!(!buf.contains("mkey_rule_matches_total{"));
+    }
 
-        tracing::debug!(
-            message = "cleaned up unused label sets",
-            counts_series_cleaned = counts_removed,
-            sizes_series_cleaned = sizes_removed
+    #[test]
+    fn test_max_series_all_servers() {
+        let metrics = Metrics::new();
+        metrics.set_limits(Limits {
+            max_series: Some(2),
+            ..Default::default()
+        });
+        metrics.publish("cache-a:11211", new_cycle("cart", 3));
+        metrics.publish("cache-b:11211", new_cycle_labels(&[("profile", 2), ("session", 1)]));
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"cart\"} 3\n"));
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-b:11211\",thing=\"__other__\"} 3\n"));
+        assert_eq!(
+            2,
+            buf.lines().filter(|l| l.starts_with("mkey_memcached_counts{")).count()
         );
+        assert!(buf.contains("mkey_series_dropped{server=\"cache-a:11211\"} 0\n"));
+        assert!(buf.contains("mkey_series_dropped{server=\"cache-b:11211\"} 2\n"));
+    }
+
+    #[test]
+    fn test_max_values_all_servers() {
+        let mut limits = Limits::default();
+        limits.max_values.insert("thing".to_owned(), 1);
+        let metrics = Metrics::new();
+        metrics.set_limits(limits);
+        metrics.publish("cache-a:11211", new_cycle("cart", 3));
+        metrics.publish("cache-b:11211", new_cycle("profile", 2));
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"cart\"} 3\n"));
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-b:11211\",thing=\"__other__\"} 2\n"));
+        assert!(buf.contains("mkey_label_values_dropped{server=\"cache-a:11211\",label_name=\"thing\"} 0\n"));
+        assert!(buf.contains("mkey_label_values_dropped{server=\"cache-b:11211\",label_name=\"thing\"} 1\n"));
+    }
+
+    #[test]
+    fn test_set_limits_after_publish() {
+        let metrics = Metrics::new();
+        metrics.publish("cache-a:11211", new_cycle_labels(&[("cart", 3), ("profile", 2)]));
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"profile\"} 2\n"));
+
+        let mut limits = Limits::default();
+        limits.max_values.insert("thing".to_owned(), 1);
+        metrics.set_limits(limits);
+
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"__other__\"} 2\n"));
+        assert!(!buf.contains("thing=\"profile\""));
+    }
+
+    #[test]
+    fn test_encode_results() {
+        let metrics = Metrics::new();
+        metrics.publish("cache-a:11211", new_cycle("cart", 3));
+
+        let mut buf = String::new();
+        metrics.encode_results(&mut buf).unwrap();
+
+        assert!(buf.contains("mkey_memcached_counts{server=\"cache-a:11211\",thing=\"cart\"} 3\n"));
+        assert!(buf.contains("mkey_memcached_sizes{server=\"cache-a:11211\",thing=\"cart\"} 30\n"));
+        assert!(!buf.contains("mkey_config_reload_success"));
+        assert!(!buf.contains("mkey_updates"));
+        assert!(!buf.contains("mkey_up"));
+        assert!(!buf.contains("mkey_rule_"));
+        assert!(buf.ends_with("# EOF\n"));
     }
 }
@@ -0,0 +1,631 @@
+use crate::aggregate::{Aggregates, Aggregator, LabelCounts};
+use crate::config::RuleGroup;
+use crate::keys::{LabelParser, RuleStats};
+use crate::limits::{LimitStats, Limiter};
+use crate::sample::{key_hash, Sampler};
+use crate::source::MetaSink;
+use mtop_client::Meta;
+use std::future::Future;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+/// Number of keys sent to a worker thread at once.
+const BATCH_SIZE: usize = 1024;
+
+/// Number of batches that may be waiting for each worker thread before adding more
+/// keys waits. This bounds memory used when workers can't keep up with the servers.
+const QUEUE_SIZE: usize = 4;
+
+/// Work run by a thread of a `WorkerPool`.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Aggregated counts and sizes for all keys from a single server.
+#[derive(Debug)]
+pub struct Aggregation {
+    pub aggregates: Aggregates,
+    pub dropped: LabelCounts,
+    /// Keys that no rule set a label for, whether they were dropped or not.
+    pub unmatched: LabelCounts,
+    pub limits: LimitStats,
+    /// Matches and time taken by each rule.
+    pub rules: RuleStats,
+    /// Number of keys fetched, including keys not part of the sample.
+    pub num_keys: usize,
+    /// Fraction of keys processed. When less than 1, counts and sizes are estimates.
+    pub sample_rate: f64,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Self {
+            aggregates: Aggregates::new(),
+            dropped: LabelCounts::default(),
+            unmatched: LabelCounts::default(),
+            limits: LimitStats::default(),
+            rules: RuleStats::default(),
+            num_keys: 0,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// Extract labels from keys, aggregate them by label set, and apply cardinality limits.
+/// This is the same process used for each update of exported metrics.
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    parser: LabelParser<'a>,
+    aggregator: Aggregator<'a>,
+    limiter: Limiter<'a>,
+    sampler: Sampler,
+    aggregates: Aggregates,
+    dropped: LabelCounts,
+    unmatched: LabelCounts,
+    rules: RuleStats,
+    num_keys: usize,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Create a new `Pipeline` using `now` to compute the remaining time-to-live of keys.
+    pub fn new(config: &'a RuleGroup, now: SystemTime) -> Self {
+        Self {
+            parser: LabelParser::new(config),
+            aggregator: Aggregator::new(config, now),
+            limiter: Limiter::new(&config.limits),
+            sampler: Sampler::default(),
+            aggregates: Aggregates::new(),
+            dropped: LabelCounts::default(),
+            unmatched: LabelCounts::default(),
+            rules: RuleStats::new(config.rules.len()),
+            num_keys: 0,
+        }
+    }
+
+    /// Create a new `Pipeline` that continues from the partial results of a pipeline created
+    /// with the same configuration.
+    pub fn resume(config: &'a RuleGroup, now: SystemTime, partial: Aggregation) -> Self {
+        Self {
+            aggregates: partial.aggregates,
+            dropped: partial.dropped,
+            unmatched: partial.unmatched,
+            rules: partial.rules,
+            num_keys: partial.num_keys,
+            ..Self::new(config, now)
+        }
+    }
+
+    /// Only process keys picked by `sampler` and scale counts and sizes up to estimate
+    /// totals for all keys when finished.
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Add a single key to the counts of the label set extracted from it, if the key is
+    /// part of the sample.
+    pub fn add(&mut self, meta: &Meta) {
+        self.num_keys += 1;
+        if !self.sampler.is_sampled(&meta.key) {
+            return;
+        }
+
+        let extraction = self.parser.apply(meta, Some(&mut self.rules));
+        if extraction.unmatched {
+            self.aggregator.add(&mut self.unmatched, meta);
+        }
+
+        match extraction.labels {
+            Some(labels) => self.aggregator.add(self.aggregates.entry(labels).or_default(), meta),
+            None => self.dropped.add(meta),
+        }
+    }
+
+    /// Merge partial results from another pipeline, computed with the same configuration,
+    /// into this one.
+    pub fn merge(&mut self, other: Aggregation) {
+        for (labels, counts) in other.aggregates {
+            self.aggregates.entry(labels).or_default().merge(&counts);
+        }
+
+        self.dropped.merge(&other.dropped);
+        self.unmatched.merge(&other.unmatched);
+        self.rules.merge(&other.rules);
+
+        self.num_keys += other.num_keys;
+    }
+
+    /// Return the results without applying cardinality limits, to be merged with results
+    /// from other pipelines.
+    pub fn partial(self) -> Aggregation {
+        Aggregation {
+            aggregates: self.aggregates,
+            dropped: self.dropped,
+            unmatched: self.unmatched,
+            limits: LimitStats::default(),
+            rules: self.rules,
+            num_keys: self.num_keys,
+            sample_rate: self.sampler.rate(),
+        }
+    }
+
+    /// Scale counts and sizes if only a sample of keys was processed, apply cardinality
+    /// limits to all label sets, and return the results.
+    pub fn finish(mut self) -> Aggregation {
+        if !self.sampler.is_complete() {
+            let factor = 1.0 / self.sampler.rate();
+            self.aggregates.values_mut().for_each(|c| c.scale(factor));
+            self.dropped.scale(factor);
+            self.unmatched.scale(factor);
+            self.rules.scale(factor);
+        }
+
+        let (aggregates, limits) = self.limiter.apply(self.aggregates);
+        Aggregation {
+            aggregates,
+            dropped: self.dropped,
+            unmatched: self.unmatched,
+            limits,
+            rules: self.rules,
+            num_keys: self.num_keys,
+            sample_rate: self.sampler.rate(),
+        }
+    }
+}
+
+/// Number of threads and shards used to extract labels and aggregate keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Workers {
+    pub threads: usize,
+    pub shards: usize,
+}
+
+impl Workers {
+    /// Returns true if more than a single shard or thread are used.
+    pub fn is_parallel(&self) -> bool {
+        self.threads > 1 || self.shards > 1
+    }
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self { threads: 1, shards: 1 }
+    }
+}
+
+/// Threads that extract labels from keys and aggregate them for every `ParallelPipeline`.
+///
+/// A single pool is shared by all servers so the number of threads is fixed, no matter how
+/// many servers are crawled at the same time. Threads take batches of keys from a queue
+/// shared by all pipelines and stop once the pool and every pipeline using it are dropped.
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+    workers: Workers,
+    jobs: Option<mpsc::Sender<Job>>,
+}
+
+impl WorkerPool {
+    /// Create a new `WorkerPool` and start its threads. Threads are only started when
+    /// `workers` is parallel, otherwise keys are expected to be processed by a `Pipeline`.
+    pub fn new(workers: Workers) -> Self {
+        if !workers.is_parallel() {
+            return Self { workers, jobs: None };
+        }
+
+        let threads = workers.threads.max(1);
+        let (tx, rx) = mpsc::channel::<Job>(threads * QUEUE_SIZE);
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("mkey-worker-{}", i))
+                .spawn(move || loop {
+                    // Only one thread waits for the next job at a time, the others wait for
+                    // the lock. The lock is released before the job runs.
+                    let job = match rx.lock().unwrap().blocking_recv() {
+                        Some(j) => j,
+                        None => break,
+                    };
+
+                    // A panic only fails the pipeline the job belongs to, which notices when
+                    // finished, and leaves the thread running for other pipelines.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("unable to start worker thread");
+        }
+
+        Self {
+            workers,
+            jobs: Some(tx),
+        }
+    }
+
+    pub fn workers(&self) -> Workers {
+        self.workers
+    }
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self::new(Workers::default())
+    }
+}
+
+/// Extract labels from keys and aggregate them using the threads of a `WorkerPool`.
+///
+/// Each key is assigned to a shard based on a hash of the key and every shard has its
+/// own partial aggregates. Batches of keys for a shard may be processed by any thread of
+/// the pool but only by one thread at a time. When finished, the partial aggregates are
+/// merged and cardinality limits are applied to the result.
+///
+/// Adding keys and finishing are async so that waiting for busy worker threads never
+/// blocks a thread used by the runtime. Dropping the pipeline before it is finished, e.g.
+/// when an update times out, skips any batches still queued.
+#[derive(Debug)]
+pub struct ParallelPipeline {
+    config: Arc<RuleGroup>,
+    now: SystemTime,
+    sampler: Sampler,
+    skipped: usize,
+    batches: Vec<Vec<Meta>>,
+    shards: Vec<Arc<Mutex<Aggregation>>>,
+    jobs: mpsc::Sender<Job>,
+    // Each queued batch holds a clone of `pending` until it has been processed, so once
+    // this pipeline drops its own sender, `done` only returns `None` after every batch.
+    pending: Option<mpsc::Sender<()>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl ParallelPipeline {
+    /// Create a new `ParallelPipeline` that processes keys using threads from `pool`, which
+    /// must have been created with parallel `Workers`.
+    pub fn new(config: Arc<RuleGroup>, now: SystemTime, pool: &WorkerPool) -> Self {
+        let shards = pool.workers.shards.max(1);
+        let jobs = pool.jobs.clone().expect("worker pool has no threads");
+        let (pending, done) = mpsc::channel(1);
+
+        Self {
+            config: config.clone(),
+            now,
+            sampler: Sampler::default(),
+            skipped: 0,
+            batches: vec![Vec::with_capacity(BATCH_SIZE); shards],
+            shards: (0..shards)
+                .map(|_| Arc::new(Mutex::new(Pipeline::new(&config, now).partial())))
+                .collect(),
+            jobs,
+            pending: Some(pending),
+            done,
+        }
+    }
+
+    /// Only process keys picked by `sampler` and scale counts and sizes up to estimate
+    /// totals for all keys when finished.
+    pub fn with_sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Queue a single key to be added to the shard it belongs to, if the key is part of the
+    /// sample. This waits if the worker threads have too many batches waiting.
+    pub async fn add(&mut self, meta: &Meta) {
+        if !self.sampler.is_sampled(&meta.key) {
+            self.skipped += 1;
+            return;
+        }
+
+        let shard = (key_hash(&meta.key) % self.batches.len() as u64) as usize;
+        let batch = &mut self.batches[shard];
+        batch.push(meta.clone());
+
+        if batch.len() >= BATCH_SIZE {
+            let batch = mem::replace(batch, Vec::with_capacity(BATCH_SIZE));
+            self.send(shard, batch).await;
+        }
+    }
+
+    /// Wait for worker threads to process all queued keys, merge their partial aggregates,
+    /// and apply cardinality limits. Merging is done on a thread for blocking work.
+    pub async fn finish(mut self) -> Aggregation {
+        for shard in 0..self.batches.len() {
+            let batch = mem::take(&mut self.batches[shard]);
+            if !batch.is_empty() {
+                self.send(shard, batch).await;
+            }
+        }
+
+        self.pending = None;
+        let _ = self.done.recv().await;
+
+        let shards = mem::take(&mut self.shards);
+        let (config, now, sampler, skipped) = (self.config.clone(), self.now, self.sampler, self.skipped);
+        tokio::task::spawn_blocking(move || {
+            let mut out = Pipeline::new(&config, now).with_sampler(sampler);
+            out.num_keys += skipped;
+            for shard in shards {
+                let partial = mem::take(&mut *shard.lock().expect("worker thread panicked"));
+                out.merge(partial);
+            }
+
+            out.finish()
+        })
+        .await
+        .expect("merging partial aggregates panicked")
+    }
+
+    async fn send(&self, shard: usize, batch: Vec<Meta>) {
+        let (config, now, partial) = (self.config.clone(), self.now, self.shards[shard].clone());
+        let pending = self.pending.clone();
+        let job: Job = Box::new(move || {
+            // Nothing else refers to the shard if the pipeline was dropped before finishing.
+            if Arc::strong_count(&partial) == 1 {
+                return;
+            }
+
+            // A poisoned lock means processing an earlier batch panicked, which is raised
+            // when finished, so there's no point processing more keys for the shard.
+            if let Ok(mut partial) = partial.lock() {
+                let mut pipeline = Pipeline::resume(&config, now, mem::take(&mut *partial));
+                batch.iter().for_each(|m| pipeline.add(m));
+                *partial = pipeline.partial();
+            }
+
+            drop(pending);
+        });
+
+        // Sending only fails if every worker thread has stopped, which can only happen
+        // once the pool is dropped.
+        let _ = self.jobs.send(job).await;
+    }
+}
+
+impl MetaSink for ParallelPipeline {
+    fn add(&mut self, meta: &Meta) -> impl Future<Output = ()> + Send {
+        ParallelPipeline::add(self, meta)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ParallelPipeline, Pipeline, WorkerPool, Workers};
+    use crate::config::{Action, Limits, Rule, RuleGroup, RulePattern};
+    use crate::sample::Sampler;
+    use mtop_client::Meta;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    fn new_meta(key: &str, size: u64) -> Meta {
+        Meta {
+            key: key.to_owned(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    fn new_group() -> RuleGroup {
+        RuleGroup {
+            name: "test".to_owned(),
+            rules: vec![
+                Rule {
+                    pattern: RulePattern::new(r"^lock:").unwrap(),
+                    action: Action::Drop,
+                    label_name: "".to_owned(),
+                    label_value: "".to_owned(),
+                },
+                Rule {
+                    pattern: RulePattern::new(r"^(\w+):").unwrap(),
+                    action: Action::Label,
+                    label_name: "type".to_owned(),
+                    label_value: "$1".to_owned(),
+                },
+            ],
+            limits: Limits {
+                max_series: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let group = new_group();
+        let mut pipeline = Pipeline::new(&group, SystemTime::now());
+        pipeline.add(&new_meta("lock:1", 10));
+        pipeline.add(&new_meta("cart:1", 20));
+        pipeline.add(&new_meta("cart:2", 20));
+        pipeline.add(&new_meta("profile:1", 30));
+        pipeline.add(&new_meta("session:1", 40));
+
+        let res = pipeline.finish();
+        let cart = vec![("type".to_owned(), "cart".to_owned())];
+        let other = vec![("type".to_owned(), "__other__".to_owned())];
+
+        assert_eq!(5, res.num_keys);
+        assert_eq!(1, res.dropped.count);
+        assert_eq!(10, res.dropped.size);
+        assert_eq!(2, res.aggregates.len());
+        assert_eq!(40, res.aggregates[&cart].size);
+        assert_eq!(70, res.aggregates[&other].size);
+        assert_eq!(2, res.limits.series_dropped);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_pipeline() {
+        let group = Arc::new(new_group());
+        let now = SystemTime::now();
+        let metas: Vec<Meta> = (0..5000)
+            .map(|i| new_meta(&format!("{}:{}", ["lock", "cart", "profile"][i % 3], i), 10))
+            .collect();
+
+        let mut serial = Pipeline::new(&group, now);
+        metas.iter().for_each(|m| serial.add(m));
+        let expected = serial.finish();
+
+        let pool = WorkerPool::new(Workers { threads: 3, shards: 8 });
+        let mut parallel = ParallelPipeline::new(group.clone(), now, &pool);
+        for m in metas.iter() {
+            parallel.add(m).await;
+        }
+        let res = parallel.finish().await;
+
+        assert_eq!(expected.num_keys, res.num_keys);
+        assert_eq!(expected.dropped, res.dropped);
+        assert_eq!(expected.aggregates, res.aggregates);
+        assert_eq!(expected.limits, res.limits);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parallel_pipeline_shared_pool() {
+        let group = Arc::new(new_group());
+        let now = SystemTime::now();
+        let metas: Arc<Vec<Meta>> = Arc::new(
+            (0..5000)
+                .map(|i| new_meta(&format!("{}:{}", ["lock", "cart", "profile"][i % 3], i), 10))
+                .collect(),
+        );
+
+        let mut serial = Pipeline::new(&group, now);
+        metas.iter().for_each(|m| serial.add(m));
+        let expected = serial.finish();
+
+        // A pipeline dropped before finishing must not affect others using the same pool.
+        let pool = WorkerPool::new(Workers { threads: 2, shards: 4 });
+        let mut abandoned = ParallelPipeline::new(group.clone(), now, &pool);
+        for m in metas.iter() {
+            abandoned.add(m).await;
+        }
+        drop(abandoned);
+
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let (group, metas, pool) = (group.clone(), metas.clone(), pool.clone());
+            tasks.push(tokio::spawn(async move {
+                let mut parallel = ParallelPipeline::new(group, now, &pool);
+                for m in metas.iter() {
+                    parallel.add(m).await;
+                }
+                parallel.finish().await
+            }));
+        }
+
+        for task in tasks {
+            let res = task.await.unwrap();
+            assert_eq!(expected.num_keys, res.num_keys);
+            assert_eq!(expected.dropped, res.dropped);
+            assert_eq!(expected.aggregates, res.aggregates);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_unmatched_and_rule_stats() {
+        let group = Arc::new(new_group());
+        let now = SystemTime::now();
+        let metas: Vec<Meta> = (0..3000)
+            .map(|i| new_meta(&format!("{}{}", ["lock:", "cart:", "-"][i % 3], i), 10))
+            .collect();
+
+        let mut serial = Pipeline::new(&group, now);
+        metas.iter().for_each(|m| serial.add(m));
+        let serial = serial.finish();
+
+        let pool = WorkerPool::new(Workers { threads: 3, shards: 8 });
+        let mut parallel = ParallelPipeline::new(group.clone(), now, &pool);
+        for m in metas.iter() {
+            parallel.add(m).await;
+        }
+        let parallel = parallel.finish().await;
+
+        assert_eq!(1000, serial.unmatched.count);
+        assert_eq!(10000, serial.unmatched.size);
+        assert_eq!(vec![1000, 1000], serial.rules.matches);
+        assert_eq!(serial.unmatched, parallel.unmatched);
+        assert_eq!(serial.rules.matches, parallel.rules.matches);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_sampled() {
+        let group = Arc::new(RuleGroup {
+            limits: Limits::default(),
+            ..new_group()
+        });
+        let now = SystemTime::now();
+        let sampler = Sampler::new(0.25);
+
+        let mut serial = Pipeline::new(&group, now).with_sampler(sampler);
+        let pool = WorkerPool::new(Workers { threads: 2, shards: 4 });
+        let mut parallel = ParallelPipeline::new(group.clone(), now, &pool).with_sampler(sampler);
+        for i in 0..10000 {
+            let m = new_meta(&format!("cart:{}", i), 10);
+            serial.add(&m);
+            parallel.add(&m).await;
+        }
+
+        let serial = serial.finish();
+        let parallel = parallel.finish().await;
+        let cart = vec![("type".to_owned(), "cart".to_owned())];
+
+        assert_eq!(10000, serial.num_keys);
+        assert_eq!(0.25, serial.sample_rate);
+        assert_eq!(serial.aggregates, parallel.aggregates);
+        assert_eq!(serial.num_keys, parallel.num_keys);
+        // The estimate should be close to the real number of keys.
+        let count = serial.aggregates[&cart].count;
+        assert!(count > 9000 && count < 11000, "estimated count {}", count);
+        assert_eq!(count * 10, serial.aggregates[&cart].size);
+        // Rule matches are estimated the same way as counts.
+        assert_eq!(count as u64, serial.rules.matches[1]);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_pipeline_slow_worker() {
+        // Many rules that never match make the worker much slower than adding keys, so
+        // adding keys has to wait for it. On a current-thread runtime, other tasks only get
+        // to run if that waiting yields to the runtime instead of blocking its only thread.
+        let rules = (0..100)
+            .map(|i| Rule {
+                pattern: RulePattern::new(&format!(r"^prefix{}:(\w+)", i)).unwrap(),
+                action: Action::Label,
+                label_name: "type".to_owned(),
+                label_value: "$1".to_owned(),
+            })
+            .collect();
+        let group = Arc::new(RuleGroup {
+            name: "test".to_owned(),
+            rules,
+            ..Default::default()
+        });
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let pool = WorkerPool::new(Workers { threads: 1, shards: 2 });
+        let mut parallel = ParallelPipeline::new(group, SystemTime::now(), &pool);
+        for i in 0..10000 {
+            parallel.add(&new_meta(&format!("cart:{}", i), 10)).await;
+        }
+
+        assert!(
+            ticks.load(Ordering::Relaxed) > 0,
+            "other tasks never ran while adding keys"
+        );
+
+        let res = parallel.finish().await;
+        ticker.abort();
+
+        assert_eq!(10000, res.num_keys);
+        assert_eq!(10000, res.unmatched.count);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::pipeline::Aggregation;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Counts and sizes for a single label set from a single server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportRow {
+    pub server: String,
+    pub labels: Vec<(String, String)>,
+    pub count: i64,
+    pub size: i64,
+}
+
+/// Counts and sizes of every label set from one or more servers, sorted by size with
+/// the largest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub rows: Vec<ReportRow>,
+}
+
+impl Report {
+    pub fn new<'a, I>(results: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a Aggregation)>,
+    {
+        let mut rows = Vec::new();
+        for (server, aggregation) in results {
+            for (labels, counts) in aggregation.aggregates.iter() {
+                rows.push(ReportRow {
+                    server: server.to_owned(),
+                    labels: labels.clone(),
+                    count: counts.count,
+                    size: counts.size,
+                });
+            }
+        }
+
+        rows.sort_by(|r1, r2| {
+            r2.size
+                .cmp(&r1.size)
+                .then_with(|| r1.server.cmp(&r2.server))
+                .then_with(|| r1.labels.cmp(&r2.labels))
+        });
+
+        Self { rows }
+    }
+
+    /// Format as a table with aligned columns meant for people to read.
+    pub fn table(&self) -> String {
+        let labels: Vec<String> = self.rows.iter().map(|r| format_labels(&r.labels)).collect();
+        let headers = ["SERVER", "LABELS", "COUNT", "SIZE"];
+        let mut widths = headers.map(|h| h.len());
+
+        for (row, labels) in self.rows.iter().zip(labels.iter()) {
+            widths[0] = widths[0].max(row.server.len());
+            widths[1] = widths[1].max(labels.len());
+            widths[2] = widths[2].max(row.count.to_string().len());
+            widths[3] = widths[3].max(row.size.to_string().len());
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}",
+            headers[0],
+            headers[1],
+            headers[2],
+            headers[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+
+        for (row, labels) in self.rows.iter().zip(labels.iter()) {
+            let _ = writeln!(
+                out,
+                "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}",
+                row.server,
+                labels,
+                row.count,
+                row.size,
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            );
+        }
+
+        out
+    }
+
+    /// Format as a JSON array of objects with `server`, `labels`, `count`, and `size` fields.
+    pub fn json(&self) -> Result<String, serde_json::Error> {
+        #[derive(Serialize)]
+        struct JsonRow<'a> {
+            server: &'a str,
+            labels: BTreeMap<&'a str, &'a str>,
+            count: i64,
+            size: i64,
+        }
+
+        let rows: Vec<JsonRow> = self
+            .rows
+            .iter()
+            .map(|r| JsonRow {
+                server: &r.server,
+                labels: r.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+                count: r.count,
+                size: r.size,
+            })
+            .collect();
+
+        serde_json::to_string_pretty(&rows)
+    }
+
+    /// Format as CSV with a column for the server, each label name, the count, and the size.
+    /// Label columns are empty for label sets that don't include a particular label.
+    pub fn csv(&self) -> String {
+        let mut names: Vec<&str> = Vec::new();
+        for row in self.rows.iter() {
+            for (name, _) in row.labels.iter() {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+
+        let mut out = String::new();
+        let mut header = vec!["server"];
+        header.extend(names.iter());
+        header.extend(["count", "size"]);
+        out.push_str(&header.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","));
+        out.push('\n');
+
+        for row in self.rows.iter() {
+            let mut fields = vec![csv_field(&row.server)];
+            for name in names.iter() {
+                let value = row.labels.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+                fields.push(csv_field(value.unwrap_or_default()));
+            }
+
+            fields.push(row.count.to_string());
+            fields.push(row.size.to_string());
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+fn format_labels(labels: &[(String, String)]) -> String {
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={:?}", k, v)).collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Report;
+    use crate::aggregate::LabelCounts;
+    use crate::pipeline::Aggregation;
+
+    fn new_aggregation() -> Aggregation {
+        let mut aggregation = Aggregation::default();
+        aggregation.aggregates.insert(
+            vec![("type".to_owned(), "cart".to_owned())],
+            LabelCounts {
+                count: 2,
+                size: 100,
+                ..Default::default()
+            },
+        );
+        aggregation.aggregates.insert(
+            vec![
+                ("type".to_owned(), "profile".to_owned()),
+                ("user".to_owned(), "a,b".to_owned()),
+            ],
+            LabelCounts {
+                count: 1,
+                size: 5000,
+                ..Default::default()
+            },
+        );
+        aggregation
+    }
+
+    #[test]
+    fn test_report_table() {
+        let aggregation = new_aggregation();
+        let report = Report::new([("cache01:11211", &aggregation)]);
+
+        assert_eq!(
+            concat!(
+                "SERVER         LABELS                        COUNT  SIZE\n",
+                "cache01:11211  {type=\"profile\", user=\"a,b\"}      1  5000\n",
+                "cache01:11211  {type=\"cart\"}                     2   100\n",
+            ),
+            report.table()
+        );
+    }
+
+    #[test]
+    fn test_report_csv() {
+        let aggregation = new_aggregation();
+        let report = Report::new([("cache01:11211", &aggregation)]);
+
+        assert_eq!(
+            concat!(
+                "server,type,user,count,size\n",
+                "cache01:11211,profile,\"a,b\",1,5000\n",
+                "cache01:11211,cart,,2,100\n",
+            ),
+            report.csv()
+        );
+    }
+
+    #[test]
+    fn test_report_json() {
+        let aggregation = new_aggregation();
+        let report = Report::new([("cache01:11211", &aggregation)]);
+        let parsed: serde_json::Value = serde_json::from_str(&report.json().unwrap()).unwrap();
+
+        assert_eq!(
+            serde_json::json!([
+                {"server": "cache01:11211", "labels": {"type": "profile", "user": "a,b"}, "count": 1, "size": 5000},
+                {"server": "cache01:11211", "labels": {"type": "cart"}, "count": 2, "size": 100},
+            ]),
+            parsed
+        );
+    }
+}
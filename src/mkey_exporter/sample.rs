@@ -0,0 +1,142 @@
+use crate::config::Sampling;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Decide which keys to process when only a sample of keys is used.
+///
+/// Keys are picked based on a hash of the key so that the same keys are picked every
+/// update, as long as the rate doesn't change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampler {
+    rate: f64,
+    threshold: u64,
+}
+
+impl Sampler {
+    /// Create a new `Sampler` that picks approximately `rate` of all keys, clamped to be
+    /// between 0 and 1.
+    pub fn new(rate: f64) -> Self {
+        let rate = if rate.is_nan() { 1.0 } else { rate.clamp(0.0, 1.0) };
+        let threshold = if rate >= 1.0 {
+            u64::MAX
+        } else {
+            (rate * u64::MAX as f64) as u64
+        };
+
+        Self { rate, threshold }
+    }
+
+    /// Create a new `Sampler` based on optional sampling configuration. `num_keys` is the
+    /// number of keys expected to be fetched and is used to pick a rate when a `max_keys`
+    /// budget is set instead of a fixed rate.
+    pub fn from_config(config: Option<&Sampling>, num_keys: u64) -> Self {
+        match config {
+            Some(Sampling { rate: Some(rate), .. }) => Self::new(*rate),
+            Some(Sampling {
+                max_keys: Some(max), ..
+            }) if num_keys > *max => Self::new(*max as f64 / num_keys as f64),
+            _ => Self::default(),
+        }
+    }
+
+    /// Fraction of keys picked, between 0 and 1.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Returns true if this `Sampler` picks every key.
+    pub fn is_complete(&self) -> bool {
+        self.rate >= 1.0
+    }
+
+    /// Returns true if `key` is part of the sample.
+    pub fn is_sampled(&self, key: &str) -> bool {
+        self.is_complete() || key_hash(key) < self.threshold
+    }
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+/// Hash of a key that is the same every time the process runs and for every version of
+/// Rust, used to pick sampled keys and shards.
+///
+/// This is 64-bit FNV-1a followed by the 64-bit finalizer from MurmurHash3. FNV-1a alone
+/// doesn't mix short keys that differ in the last few bytes well enough for picking keys
+/// by comparing the hash to a threshold. Changing this changes which keys are sampled.
+pub(crate) fn key_hash(key: &str) -> u64 {
+    let mut h = FNV_OFFSET_BASIS;
+    for b in key.bytes() {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// Random `u64` that differs on every call, unlike `key_hash`. Not suitable for anything
+/// security related.
+pub fn random_u64() -> u64 {
+    // Each `RandomState` is seeded with different random keys, which is enough randomness
+    // to spread out retries or seed a PRNG without pulling in a dependency for it.
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key_hash, random_u64, Sampler};
+    use crate::config::Sampling;
+
+    #[test]
+    fn test_key_hash() {
+        // Sampled keys must not change between releases so these values must not change.
+        assert_eq!(0xefd01f60ba992926, key_hash(""));
+        assert_eq!(0x82a2a958a9bece5b, key_hash("a"));
+        assert_eq!(0xa39fa7879c86467c, key_hash("cart:user-1"));
+    }
+
+    #[test]
+    fn test_random_u64() {
+        // Two identical values in a row is possible but vanishingly unlikely.
+        assert_ne!(random_u64(), random_u64());
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        let sampler = Sampler::new(0.1);
+        let keys: Vec<String> = (0..10000).map(|i| format!("key:{}", i)).collect();
+        let sampled: Vec<&String> = keys.iter().filter(|k| sampler.is_sampled(k)).collect();
+
+        assert!(sampled.len() > 900 && sampled.len() < 1100, "sampled {}", sampled.len());
+        // The same keys are picked every time.
+        assert!(sampled.iter().all(|k| Sampler::new(0.1).is_sampled(k)));
+        assert!(keys.iter().all(|k| Sampler::new(1.0).is_sampled(k)));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let rate = Sampling {
+            rate: Some(0.25),
+            max_keys: None,
+        };
+        let max_keys = Sampling {
+            rate: None,
+            max_keys: Some(1000),
+        };
+
+        assert_eq!(1.0, Sampler::from_config(None, 5000).rate());
+        assert_eq!(0.25, Sampler::from_config(Some(&rate), 5000).rate());
+        assert_eq!(0.2, Sampler::from_config(Some(&max_keys), 5000).rate());
+        assert_eq!(1.0, Sampler::from_config(Some(&max_keys), 500).rate());
+    }
+}
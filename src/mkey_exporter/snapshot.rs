@@ -0,0 +1,248 @@
+use crate::crawler;
+use mtop_client::Meta;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const SERVER_PREFIX: &str = "server=";
+
+/// Zstd compressed snapshot being written to a temporary file next to its destination.
+///
+/// The destination is only replaced when `finish` succeeds so that a failed fetch or write
+/// never leaves a partial snapshot behind or removes a previous one. The temporary file is
+/// removed if the writer is dropped without being finished.
+pub struct Writer {
+    encoder: Option<zstd::Encoder<'static, File>>,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl Writer {
+    /// Compress any buffered data and move the snapshot to its destination, replacing it
+    /// if it exists.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            let file = encoder.finish()?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&self.tmp, &self.path)
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("snapshot already finished"))?
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.encoder.as_mut() {
+            Some(e) => e.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Nothing to clean up after a successful rename, the temporary file doesn't exist
+        // anymore. Otherwise, make sure a partial snapshot isn't left behind.
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
+
+/// Create a new zstd compressed snapshot that replaces the file at `path` once it has
+/// been finished successfully.
+pub fn create(path: &Path) -> io::Result<Writer> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "snapshot path is not a file"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = path.with_file_name(tmp_name);
+
+    let encoder = zstd::Encoder::new(File::create(&tmp)?, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    Ok(Writer {
+        encoder: Some(encoder),
+        tmp,
+        path: path.to_owned(),
+    })
+}
+
+/// Open a zstd compressed snapshot file at `path` for reading.
+pub fn open(path: &Path) -> io::Result<BufReader<zstd::Decoder<'static, BufReader<File>>>> {
+    Ok(BufReader::new(zstd::Decoder::new(File::open(path)?)?))
+}
+
+/// Start the keys fetched from `server` in a snapshot. All keys written until the next
+/// server are attributed to this server.
+pub fn write_server<W: Write>(w: &mut W, server: &str) -> io::Result<()> {
+    writeln!(w, "{}{}", SERVER_PREFIX, server)
+}
+
+/// Write metadata for a single key to a snapshot, in the same format used by the Memcached
+/// `lru_crawler metadump` command, limited to the fields used by `Meta`.
+pub fn write_meta<W: Write>(w: &mut W, meta: &Meta) -> io::Result<()> {
+    writeln!(
+        w,
+        "key={} exp={} size={}",
+        urlencoding::encode(&meta.key),
+        meta.expires,
+        meta.size
+    )
+}
+
+/// Read metadata for every key in a snapshot, calling `f` with the server each key was
+/// fetched from and its metadata. The same `Meta` is reused for each key to avoid allocating.
+pub fn read<R, F>(r: R, mut f: F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(&str, &Meta),
+{
+    let mut server: Option<String> = None;
+    let mut meta = Meta::default();
+
+    for (i, line) in r.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(s) = line.strip_prefix(SERVER_PREFIX) {
+            server = Some(s.to_owned());
+            continue;
+        }
+
+        match server.as_deref() {
+            Some(s) => {
+                crawler::parse_line(&line, &mut meta).map_err(|e| invalid(i, e))?;
+                f(s, &meta);
+            }
+            None => return Err(invalid(i, "key before any server")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the names of all servers in a snapshot, in the order they were written.
+pub fn servers<R: BufRead>(r: R) -> io::Result<Vec<String>> {
+    let mut out = Vec::new();
+    for line in r.lines() {
+        if let Some(s) = line?.strip_prefix(SERVER_PREFIX) {
+            if !out.iter().any(|o| o == s) {
+                out.push(s.to_owned());
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn invalid<S: AsRef<str>>(i: usize, msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", i + 1, msg.as_ref()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{create, read, servers, write_meta, write_server};
+    use mtop_client::Meta;
+    use std::fs;
+
+    fn new_meta(key: &str, expires: i64, size: u64) -> Meta {
+        Meta {
+            key: key.to_owned(),
+            expires,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_write_read() {
+        let a = [new_meta("cart:user 1", -1, 10), new_meta("cart:user-2", 1700000000, 20)];
+        let b = [new_meta("profile:user-1", 0, 30)];
+
+        let mut buf = Vec::new();
+        write_server(&mut buf, "cache-a:11211").unwrap();
+        a.iter().for_each(|m| write_meta(&mut buf, m).unwrap());
+        write_server(&mut buf, "cache-b:11211").unwrap();
+        b.iter().for_each(|m| write_meta(&mut buf, m).unwrap());
+
+        let mut out = Vec::new();
+        read(buf.as_slice(), |s, m| out.push((s.to_owned(), m.clone()))).unwrap();
+
+        assert_eq!(
+            vec![
+                ("cache-a:11211".to_owned(), a[0].clone()),
+                ("cache-a:11211".to_owned(), a[1].clone()),
+                ("cache-b:11211".to_owned(), b[0].clone()),
+            ],
+            out
+        );
+        assert_eq!(
+            vec!["cache-a:11211".to_owned(), "cache-b:11211".to_owned()],
+            servers(buf.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_metadump_format() {
+        let raw = "server=localhost:11211\nkey=cart%3Auser-1 exp=-1 la=1700000000 cas=1 fetch=no cls=1 size=64\n";
+        let mut out = Vec::new();
+        read(raw.as_bytes(), |_, m| out.push(m.clone())).unwrap();
+
+        assert_eq!(vec![new_meta("cart:user-1", -1, 64)], out);
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        let res = read("key=cart exp=-1 size=64\n".as_bytes(), |_, _| {});
+        assert!(res.is_err());
+
+        let res = read("server=a\nkey=cart exp=-1\n".as_bytes(), |_, _| {});
+        assert!(res.is_err());
+
+        let res = read("server=a\nkey=cart exp=never size=64\n".as_bytes(), |_, _| {});
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_create_replaces_on_finish() {
+        let dir = std::env::temp_dir().join(format!("mkey-snapshot-finish-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.zst");
+        fs::write(&path, b"previous").unwrap();
+
+        let mut w = create(&path).unwrap();
+        write_server(&mut w, "cache-a:11211").unwrap();
+        write_meta(&mut w, &new_meta("cart:user-1", -1, 10)).unwrap();
+        assert_eq!(b"previous".as_slice(), fs::read(&path).unwrap());
+        w.finish().unwrap();
+
+        let mut out = Vec::new();
+        read(super::open(&path).unwrap(), |s, m| out.push((s.to_owned(), m.clone()))).unwrap();
+        assert_eq!(vec![("cache-a:11211".to_owned(), new_meta("cart:user-1", -1, 10))], out);
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_keeps_previous_when_not_finished() {
+        let dir = std::env::temp_dir().join(format!("mkey-snapshot-abandon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.zst");
+        fs::write(&path, b"previous").unwrap();
+
+        let mut w = create(&path).unwrap();
+        write_server(&mut w, "cache-a:11211").unwrap();
+        drop(w);
+
+        assert_eq!(b"previous".as_slice(), fs::read(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use crate::crawler::CrawlerPool;
+use crate::snapshot;
+use mtop_client::{Meta, MtopError};
+use std::future::{self, Future};
+use std::mem;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+/// Number of keys read from a snapshot before they are handed to the sink at once.
+const SNAPSHOT_BATCH_SIZE: usize = 1024;
+
+/// Number of batches read from a snapshot that may be waiting for the sink before reading
+/// more keys blocks.
+const SNAPSHOT_QUEUE_SIZE: usize = 4;
+
+/// Receives metadata for each key as it is read from a `KeySource`.
+pub trait MetaSink {
+    /// Add metadata for a single key. No more keys are read until the returned future
+    /// completes, so sinks that can't keep up slow down reading without blocking a thread
+    /// used by the runtime.
+    fn add(&mut self, meta: &Meta) -> impl Future<Output = ()> + Send;
+}
+
+impl<F> MetaSink for F
+where
+    F: FnMut(&Meta),
+{
+    fn add(&mut self, meta: &Meta) -> impl Future<Output = ()> + Send {
+        self(meta);
+        future::ready(())
+    }
+}
+
+/// Where metadata about keys is fetched from for each server.
+#[derive(Debug)]
+pub enum KeySource {
+    /// Crawl keys from live Memcached servers.
+    Memcached(CrawlerPool),
+    /// Read keys previously recorded to a snapshot file. The file is read again for each
+    /// fetch so that it may be replaced while running.
+    Snapshot(PathBuf),
+}
+
+impl KeySource {
+    /// Get the number of keys stored on `server`, used to pick a sample rate before fetching
+    /// keys. This is the number of items reported by the server, or the number of keys for
+    /// the server in a snapshot.
+    pub async fn num_keys(&self, server: &str) -> Result<u64, MtopError> {
+        match self {
+            Self::Memcached(pool) => {
+                let mut crawler = pool.get(server).await?;
+                let num_items = crawler.num_items().await?;
+                pool.put(crawler).await;
+                Ok(num_items)
+            }
+            Self::Snapshot(path) => {
+                let (path, server) = (path.clone(), server.to_owned());
+                tokio::task::spawn_blocking(move || {
+                    let mut num_keys = 0;
+                    snapshot::read(snapshot::open(&path)?, |s, _| {
+                        if s == server {
+                            num_keys += 1;
+                        }
+                    })?;
+                    Ok(num_keys)
+                })
+                .await
+                .expect("snapshot reader panicked")
+            }
+        }
+    }
+
+    /// Add metadata for every key stored on `server` to `sink` as it is read, without
+    /// buffering all keys in memory.
+    ///
+    /// Connections are only returned to the pool after every key has been read. If this
+    /// future is dropped before finishing, e.g. due to a timeout, the connection is dropped
+    /// too since it may be in the middle of a response.
+    ///
+    /// Snapshots are read using blocking IO on a separate thread and handed to `sink` in
+    /// batches.
+    pub async fn metas<S>(&self, server: &str, sink: &mut S) -> Result<(), MtopError>
+    where
+        S: MetaSink,
+    {
+        match self {
+            Self::Memcached(pool) => {
+                let mut crawler = pool.get(server).await?;
+                crawler.metas(sink).await?;
+                pool.put(crawler).await;
+                Ok(())
+            }
+            Self::Snapshot(path) => {
+                let (tx, mut rx) = mpsc::channel(SNAPSHOT_QUEUE_SIZE);
+                let (path, server) = (path.clone(), server.to_owned());
+                let reader = tokio::task::spawn_blocking(move || {
+                    let mut batch = Vec::with_capacity(SNAPSHOT_BATCH_SIZE);
+                    snapshot::read(snapshot::open(&path)?, |s, m| {
+                        if s == server {
+                            batch.push(m.clone());
+                        }
+
+                        // Sending only fails if this future was dropped, in which case the
+                        // rest of the file is read and discarded.
+                        if batch.len() >= SNAPSHOT_BATCH_SIZE {
+                            let _ = tx.blocking_send(mem::replace(&mut batch, Vec::with_capacity(SNAPSHOT_BATCH_SIZE)));
+                        }
+                    })?;
+
+                    if !batch.is_empty() {
+                        let _ = tx.blocking_send(batch);
+                    }
+
+                    Ok::<(), MtopError>(())
+                });
+
+                while let Some(batch) = rx.recv().await {
+                    for m in batch.iter() {
+                        sink.add(m).await;
+                    }
+                }
+
+                reader.await.expect("snapshot reader panicked")
+            }
+        }
+    }
+}
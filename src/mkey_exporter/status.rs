@@ -0,0 +1,153 @@
+use crate::config::{Action, RuleGroup};
+use crate::metrics::Cycle;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Version of the exporter, shown on the status page.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Most recent error fetching keys from a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateError {
+    pub time: SystemTime,
+    pub message: String,
+}
+
+/// State of updates for a single server.
+#[derive(Debug, Clone)]
+pub struct ServerStatus {
+    pub server: String,
+    /// Results of the most recent successful update, if any.
+    pub cycle: Option<Arc<Cycle>>,
+    /// Most recent error, even if there have been successful updates since.
+    pub last_error: Option<UpdateError>,
+}
+
+/// Format the state of the exporter as plain text meant for people to read: the loaded
+/// rules and the results of the most recent update for each server.
+pub fn render(cfg: &RuleGroup, servers: &[ServerStatus], now: SystemTime) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "mkey_exporter {}", VERSION);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "rule group: {}", cfg.name);
+
+    for (i, rule) in cfg.rules.iter().enumerate() {
+        let _ = match rule.action {
+            Action::Label => writeln!(
+                out,
+                "  rules[{}]: {:?} => {}={:?}",
+                i,
+                rule.pattern.as_str(),
+                rule.label_name,
+                rule.label_value
+            ),
+            Action::Drop => writeln!(out, "  rules[{}]: {:?} => drop", i, rule.pattern.as_str()),
+        };
+    }
+
+    for status in servers {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "server: {}", status.server);
+        match &status.cycle {
+            Some(c) => {
+                let _ = writeln!(out, "  last update: {} ago", ago(now, c.completed));
+                let _ = writeln!(out, "  duration: {:.3}s", c.duration.as_secs_f64());
+                let _ = writeln!(out, "  keys scanned: {}", c.aggregation.num_keys);
+                let _ = writeln!(out, "  unique label sets: {}", c.aggregation.aggregates.len());
+            }
+            None => {
+                let _ = writeln!(out, "  last update: never");
+            }
+        }
+
+        match &status.last_error {
+            Some(e) => {
+                let _ = writeln!(out, "  last error: {} ago: {}", ago(now, e.time), e.message);
+            }
+            None => {
+                let _ = writeln!(out, "  last error: none");
+            }
+        }
+    }
+
+    out
+}
+
+fn ago(now: SystemTime, then: SystemTime) -> String {
+    format!("{}s", now.duration_since(then).map(|d| d.as_secs()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, ServerStatus, UpdateError, VERSION};
+    use crate::config::RuleGroup;
+    use crate::metrics::Cycle;
+    use crate::pipeline::Aggregation;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_render() {
+        let cfg: RuleGroup = serde_yaml::from_str(
+            r#"
+name: example
+rules:
+  - pattern: "^tmp:"
+    action: drop
+  - pattern: "^(\\w+):"
+    label_name: thing
+    label_value: "$1"
+"#,
+        )
+        .unwrap();
+
+        let now = UNIX_EPOCH + Duration::from_secs(1700000100);
+        let aggregation = Aggregation {
+            num_keys: 42,
+            ..Default::default()
+        };
+        let servers = [
+            ServerStatus {
+                server: "cache-a:11211".to_owned(),
+                cycle: Some(Arc::new(Cycle {
+                    aggregation,
+                    completed: UNIX_EPOCH + Duration::from_secs(1700000090),
+                    duration: Duration::from_millis(1500),
+                })),
+                last_error: None,
+            },
+            ServerStatus {
+                server: "cache-b:11211".to_owned(),
+                cycle: None,
+                last_error: Some(UpdateError {
+                    time: UNIX_EPOCH + Duration::from_secs(1700000095),
+                    message: "connection refused".to_owned(),
+                }),
+            },
+        ];
+
+        let expected = format!(
+            r#"mkey_exporter {}
+
+rule group: example
+  rules[0]: "^tmp:" => drop
+  rules[1]: "^(\\w+):" => thing="$1"
+
+server: cache-a:11211
+  last update: 10s ago
+  duration: 1.500s
+  keys scanned: 42
+  unique label sets: 0
+  last error: none
+
+server: cache-b:11211
+  last update: never
+  last error: 5s ago: connection refused
+"#,
+            VERSION
+        );
+
+        assert_eq!(expected, render(&cfg, &servers, now));
+    }
+}
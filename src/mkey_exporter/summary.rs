@@ -0,0 +1,352 @@
+use crate::aggregate::{LabelCounts, SampledKey};
+use crate::metrics::{self, Cycle, SERVER_LABEL};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Which total to sort label sets by, largest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    Count,
+    #[default]
+    Size,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(Self::Count),
+            "size" => Ok(Self::Size),
+            _ => Err(format!("invalid sort {:?}, must be 'count' or 'size'", s)),
+        }
+    }
+}
+
+/// How to combine, sort, and limit label sets in a summary.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SummaryQuery {
+    /// Combine label sets that have the same values for these labels, dropping all other
+    /// labels. The `server` label may be used to group by server. When not set, every label
+    /// set from every server is kept.
+    pub group_by: Option<Vec<String>>,
+    pub sort: SortBy,
+    pub limit: Option<usize>,
+}
+
+/// Label sets found by the most recent update for each server, meant to be encoded as
+/// JSON for tools that want structured data instead of Prometheus metrics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub servers: Vec<ServerSummary>,
+    pub label_sets: Vec<LabelSetSummary>,
+}
+
+/// When and how the most recent update for a server ran.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServerSummary {
+    pub server: String,
+    pub completed_timestamp: f64,
+    pub duration_seconds: f64,
+    pub num_keys: usize,
+    pub sample_rate: f64,
+}
+
+/// Totals for a single label set. Labels include the server unless grouped without it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabelSetSummary {
+    pub labels: BTreeMap<String, String>,
+    pub count: i64,
+    pub size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<TtlSummary>,
+}
+
+/// Expiration times of keys in a label set, only present when TTLs are enabled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TtlSummary {
+    pub no_expiry: i64,
+    pub expired: i64,
+    /// Number of keys that have not expired.
+    pub count: u64,
+    /// Mean remaining time-to-live of keys that have not expired.
+    pub mean_seconds: f64,
+}
+
+/// Example keys for a single label set from a single server.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabelSetKeys {
+    pub labels: BTreeMap<String, String>,
+    pub count: i64,
+    pub keys: Vec<SampledKey>,
+}
+
+/// Keys from a single server that no rule set a label for, with example keys when key
+/// samples are enabled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnmatchedKeys {
+    pub server: String,
+    pub count: i64,
+    pub size: i64,
+    pub keys: Vec<SampledKey>,
+}
+
+impl Summary {
+    pub fn new(cycles: &[(String, Arc<Cycle>)], query: &SummaryQuery) -> Self {
+        let servers = cycles
+            .iter()
+            .map(|(server, c)| ServerSummary {
+                server: server.clone(),
+                completed_timestamp: metrics::unix_timestamp(c.completed),
+                duration_seconds: c.duration.as_secs_f64(),
+                num_keys: c.aggregation.num_keys,
+                sample_rate: c.aggregation.sample_rate,
+            })
+            .collect();
+
+        let mut groups: HashMap<Vec<(String, String)>, LabelCounts> = HashMap::new();
+        for (server, c) in cycles {
+            for (labels, counts) in c.aggregation.aggregates.iter() {
+                let key = group_key(server, labels, query.group_by.as_deref());
+                groups.entry(key).or_default().merge(counts);
+            }
+        }
+
+        let mut label_sets: Vec<LabelSetSummary> = groups
+            .into_iter()
+            .map(|(labels, counts)| LabelSetSummary {
+                labels: labels.into_iter().collect(),
+                count: counts.count,
+                size: counts.size,
+                ttl: counts.ttl.map(|t| TtlSummary {
+                    no_expiry: t.no_expiry,
+                    expired: t.expired,
+                    count: t.remaining.count,
+                    mean_seconds: if t.remaining.count == 0 {
+                        0.0
+                    } else {
+                        t.remaining.sum / t.remaining.count as f64
+                    },
+                }),
+            })
+            .collect();
+
+        label_sets.sort_by(|a, b| {
+            let (ra, rb) = match query.sort {
+                SortBy::Count => (a.count, b.count),
+                SortBy::Size => (a.size, b.size),
+            };
+
+            Reverse(ra).cmp(&Reverse(rb)).then_with(|| a.labels.cmp(&b.labels))
+        });
+
+        if let Some(limit) = query.limit {
+            label_sets.truncate(limit);
+        }
+
+        Self { servers, label_sets }
+    }
+}
+
+/// Get the sampled keys of every label set from the most recent update for each server
+/// that has all the labels in `filter` with the same values. The `server` label may be used
+/// to only include a single server. Label sets are sorted by their labels.
+pub fn sampled_keys(cycles: &[(String, Arc<Cycle>)], filter: &BTreeMap<String, String>) -> Vec<LabelSetKeys> {
+    let mut out = Vec::new();
+    for (server, c) in cycles {
+        for (labels, counts) in c.aggregation.aggregates.iter() {
+            let labels: BTreeMap<String, String> = metrics::with_server(server, labels).into_iter().collect();
+            if filter.iter().all(|(n, v)| labels.get(n) == Some(v)) {
+                out.push(LabelSetKeys {
+                    labels,
+                    count: counts.count,
+                    keys: counts.keys.as_ref().map(|k| k.keys.clone()).unwrap_or_default(),
+                });
+            }
+        }
+    }
+
+    out.sort_by(|a, b| a.labels.cmp(&b.labels));
+    out
+}
+
+/// Get keys that no rule matched from the most recent update for each server.
+pub fn unmatched_keys(cycles: &[(String, Arc<Cycle>)]) -> Vec<UnmatchedKeys> {
+    cycles
+        .iter()
+        .map(|(server, c)| UnmatchedKeys {
+            server: server.clone(),
+            count: c.aggregation.unmatched.count,
+            size: c.aggregation.unmatched.size,
+            keys: c
+                .aggregation
+                .unmatched
+                .keys
+                .as_ref()
+                .map(|k| k.keys.clone())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Labels a label set is combined under: the server and all labels when not grouping,
+/// otherwise the value of each label being grouped by, empty when the label isn't set.
+fn group_key(server: &str, labels: &[(String, String)], group_by: Option<&[String]>) -> Vec<(String, String)> {
+    match group_by {
+        None => metrics::with_server(server, labels),
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                let value = if name == SERVER_LABEL {
+                    Some(server)
+                } else {
+                    labels.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+                };
+
+                (name.clone(), value.unwrap_or_default().to_owned())
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sampled_keys, unmatched_keys, SortBy, Summary, SummaryQuery};
+    use crate::aggregate::LabelCounts;
+    use crate::metrics::Cycle;
+    use crate::pipeline::Aggregation;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn new_cycle(rows: &[(&str, &str, i64, i64)]) -> Arc<Cycle> {
+        let mut aggregation = Aggregation::default();
+        for (thing, user, count, size) in rows {
+            aggregation.aggregates.insert(
+                vec![
+                    ("thing".to_owned(), thing.to_string()),
+                    ("user".to_owned(), user.to_string()),
+                ],
+                LabelCounts {
+                    count: *count,
+                    size: *size,
+                    ttl: None,
+                    item_sizes: None,
+                    keys: None,
+                },
+            );
+        }
+
+        Arc::new(Cycle {
+            aggregation,
+            completed: UNIX_EPOCH + Duration::from_secs(1700000000),
+            duration: Duration::from_secs(2),
+        })
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    fn cycles() -> Vec<(String, Arc<Cycle>)> {
+        vec![
+            (
+                "cache-a:11211".to_owned(),
+                new_cycle(&[("cart", "user-1", 3, 30), ("profile", "user-1", 1, 100)]),
+            ),
+            ("cache-b:11211".to_owned(), new_cycle(&[("cart", "user-2", 5, 20)])),
+        ]
+    }
+
+    #[test]
+    fn test_summary_all() {
+        let summary = Summary::new(&cycles(), &SummaryQuery::default());
+
+        assert_eq!(2, summary.servers.len());
+        assert_eq!(1700000000.0, summary.servers[0].completed_timestamp);
+        assert_eq!(
+            vec![
+                labels(&[("server", "cache-a:11211"), ("thing", "profile"), ("user", "user-1")]),
+                labels(&[("server", "cache-a:11211"), ("thing", "cart"), ("user", "user-1")]),
+                labels(&[("server", "cache-b:11211"), ("thing", "cart"), ("user", "user-2")]),
+            ],
+            summary.label_sets.iter().map(|s| s.labels.clone()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_summary_group_by() {
+        let query = SummaryQuery {
+            group_by: Some(vec!["thing".to_owned()]),
+            sort: SortBy::Count,
+            limit: Some(1),
+        };
+        let summary = Summary::new(&cycles(), &query);
+
+        assert_eq!(1, summary.label_sets.len());
+        assert_eq!(labels(&[("thing", "cart")]), summary.label_sets[0].labels);
+        assert_eq!(8, summary.label_sets[0].count);
+        assert_eq!(50, summary.label_sets[0].size);
+    }
+
+    #[test]
+    fn test_summary_group_by_server_and_missing() {
+        let query = SummaryQuery {
+            group_by: Some(vec!["server".to_owned(), "missing".to_owned()]),
+            ..Default::default()
+        };
+        let summary = Summary::new(&cycles(), &query);
+
+        assert_eq!(
+            vec![
+                (labels(&[("server", "cache-a:11211"), ("missing", "")]), 4, 130),
+                (labels(&[("server", "cache-b:11211"), ("missing", "")]), 5, 20),
+            ],
+            summary
+                .label_sets
+                .iter()
+                .map(|s| (s.labels.clone(), s.count, s.size))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_sampled_keys_filter() {
+        let filter = labels(&[("thing", "cart"), ("user", "user-2")]);
+        let res = sampled_keys(&cycles(), &filter);
+
+        assert_eq!(1, res.len());
+        assert_eq!(
+            labels(&[("server", "cache-b:11211"), ("thing", "cart"), ("user", "user-2")]),
+            res[0].labels
+        );
+        assert!(res[0].keys.is_empty());
+
+        let filter = labels(&[("server", "cache-a:11211")]);
+        assert_eq!(2, sampled_keys(&cycles(), &filter).len());
+    }
+
+    #[test]
+    fn test_unmatched_keys() {
+        let mut aggregation = Aggregation::default();
+        aggregation.unmatched.count = 2;
+        aggregation.unmatched.size = 64;
+        let cycles = vec![(
+            "cache-a:11211".to_owned(),
+            Arc::new(Cycle {
+                aggregation,
+                completed: UNIX_EPOCH,
+                duration: Duration::ZERO,
+            }),
+        )];
+
+        let res = unmatched_keys(&cycles);
+        assert_eq!(1, res.len());
+        assert_eq!("cache-a:11211", res[0].server);
+        assert_eq!((2, 64), (res[0].count, res[0].size));
+        assert!(res[0].keys.is_empty());
+    }
+}
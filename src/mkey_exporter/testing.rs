@@ -0,0 +1,191 @@
+use crate::config::RuleGroup;
+use crate::keys::LabelParser;
+use mtop_client::Meta;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+
+/// Results of running all tests from the `tests` section of a `RuleGroup`.
+#[derive(Debug, Default)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failures: Vec<TestFailure>,
+}
+
+impl TestResults {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A test where the labels extracted from a key didn't exactly match the expected labels.
+/// Labels are `None` when the key is (or is expected to be) dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestFailure {
+    pub index: usize,
+    pub key: String,
+    pub expected: Option<BTreeMap<String, String>>,
+    pub actual: Option<BTreeMap<String, String>>,
+}
+
+impl Display for TestFailure {
+    /// Format the failure as a diff of label names and values, one per line. Lines prefixed
+    /// with `-` are expected but missing, lines prefixed with `+` are produced but unexpected.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tests[{}]: key {:?}", self.index, self.key)?;
+
+        let empty = BTreeMap::new();
+        let expected = self.expected.as_ref().unwrap_or(&empty);
+        let actual = self.actual.as_ref().unwrap_or(&empty);
+
+        match (&self.expected, &self.actual) {
+            (None, Some(_)) => writeln!(f, "  - <dropped>")?,
+            (Some(_), None) => writeln!(f, "  + <dropped>")?,
+            _ => {}
+        }
+
+        let names: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+        for name in names {
+            match (expected.get(name), actual.get(name)) {
+                (Some(e), Some(a)) if e == a => writeln!(f, "    {}={:?}", name, e)?,
+                (e, a) => {
+                    if let Some(e) = e {
+                        writeln!(f, "  - {}={:?}", name, e)?;
+                    }
+                    if let Some(a) = a {
+                        writeln!(f, "  + {}={:?}", name, a)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run every test in `group`, comparing the labels extracted from each test key with the
+/// expected labels or expecting the key to be dropped. Labels are compared without regard
+/// to order.
+pub fn run(group: &RuleGroup) -> TestResults {
+    let parser = LabelParser::new(group);
+    let mut results = TestResults::default();
+
+    for (i, test) in group.tests.iter().enumerate() {
+        let meta = Meta {
+            key: test.key.clone(),
+            ..Default::default()
+        };
+
+        let expected = if test.dropped { None } else { Some(test.labels.clone()) };
+        let actual: Option<BTreeMap<String, String>> = parser.extract(&meta).map(|l| l.into_iter().collect());
+        if actual == expected {
+            results.passed += 1;
+        } else {
+            results.failures.push(TestFailure {
+                index: i,
+                key: test.key.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use crate::config::{Action, Rule, RuleGroup, RulePattern, RuleTest};
+    use std::collections::BTreeMap;
+
+    fn new_group(tests: Vec<RuleTest>) -> RuleGroup {
+        RuleGroup {
+            name: "test".to_owned(),
+            rules: vec![
+                Rule {
+                    pattern: RulePattern::new(r"^\w+:([\w-]+):").unwrap(),
+                    action: Action::Label,
+                    label_name: "user".to_owned(),
+                    label_value: "$1".to_owned(),
+                },
+                Rule {
+                    pattern: RulePattern::new(r"^(\w+):").unwrap(),
+                    action: Action::Label,
+                    label_name: "type".to_owned(),
+                    label_value: "$1".to_owned(),
+                },
+            ],
+            tests,
+            ..Default::default()
+        }
+    }
+
+    fn new_test(key: &str, labels: &[(&str, &str)]) -> RuleTest {
+        RuleTest {
+            key: key.to_owned(),
+            dropped: false,
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_run_success() {
+        let group = new_group(vec![
+            new_test("up:user-1:latest", &[("type", "up"), ("user", "user-1")]),
+            new_test("up", &[]),
+        ]);
+
+        let results = run(&group);
+        assert!(results.is_success());
+        assert_eq!(2, results.passed);
+    }
+
+    #[test]
+    fn test_run_failure() {
+        let group = new_group(vec![
+            new_test("up:user-1:latest", &[("type", "up"), ("user", "user-2")]),
+            new_test("up:", &[]),
+        ]);
+
+        let results = run(&group);
+        assert!(!results.is_success());
+        assert_eq!(0, results.passed);
+        assert_eq!(2, results.failures.len());
+        assert_eq!(
+            "tests[0]: key \"up:user-1:latest\"\n    type=\"up\"\n  - user=\"user-2\"\n  + user=\"user-1\"\n",
+            results.failures[0].to_string()
+        );
+        assert_eq!(
+            "tests[1]: key \"up:\"\n  + type=\"up\"\n",
+            results.failures[1].to_string()
+        );
+    }
+
+    #[test]
+    fn test_run_dropped() {
+        let mut group = new_group(vec![
+            RuleTest {
+                key: "up:user-1:latest".to_owned(),
+                dropped: true,
+                labels: BTreeMap::new(),
+            },
+            RuleTest {
+                key: "nothing".to_owned(),
+                dropped: true,
+                labels: BTreeMap::new(),
+            },
+        ]);
+        group.drop_unmatched = true;
+
+        let results = run(&group);
+        assert_eq!(1, results.passed);
+        assert_eq!(1, results.failures.len());
+        assert_eq!(
+            "tests[0]: key \"up:user-1:latest\"\n  - <dropped>\n  + type=\"up\"\n  + user=\"user-1\"\n",
+            results.failures[0].to_string()
+        );
+    }
+}
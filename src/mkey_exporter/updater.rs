@@ -0,0 +1,521 @@
+use crate::backoff::Backoff;
+use crate::config::{RuleGroup, Sampling};
+use crate::metrics::{Cycle, FailureReason, Metrics};
+use crate::pipeline::{Aggregation, ParallelPipeline, Pipeline, WorkerPool, Workers};
+use crate::sample::Sampler;
+use crate::source::KeySource;
+use crate::status::{ServerStatus, UpdateError};
+use mtop_client::{Meta, MtopError};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+
+/// Fetch keys for each server, run them through the most recently loaded rules, and
+/// publish the results to metrics.
+///
+/// Only a single update runs for each server at a time. Callers that ask for an update
+/// while one is already running for the same server wait for it to finish.
+#[derive(Debug)]
+pub struct Updater {
+    rules: watch::Receiver<Arc<RuleGroup>>,
+    source: Arc<KeySource>,
+    metrics: Arc<Metrics>,
+    pool: WorkerPool,
+    timeout: Option<Duration>,
+    backoff: Backoff,
+    /// Time the last update for each server finished, successful or not. The lock is held
+    /// for the duration of each update.
+    hosts: BTreeMap<String, Mutex<Option<Instant>>>,
+    errors: StdMutex<HashMap<String, UpdateError>>,
+}
+
+impl Updater {
+    pub fn new(
+        hosts: &[String],
+        rules: watch::Receiver<Arc<RuleGroup>>,
+        source: Arc<KeySource>,
+        metrics: Arc<Metrics>,
+        workers: Workers,
+    ) -> Self {
+        for host in hosts {
+            metrics.add_server(host);
+        }
+
+        Self {
+            rules,
+            source,
+            metrics,
+            pool: WorkerPool::new(workers),
+            timeout: None,
+            backoff: Backoff::default(),
+            hosts: hosts.iter().map(|h| (h.clone(), Mutex::new(None))).collect(),
+            errors: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Give up on fetching keys for a server when it takes longer than `timeout`, dropping
+    /// the connection so that a server that stalls mid-response cannot block updates forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry failed attempts to fetch keys for a server within a single update, waiting
+    /// between attempts based on `backoff`. An update only counts as a failure once every
+    /// attempt has failed.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Most recently loaded rules.
+    pub fn rules(&self) -> Arc<RuleGroup> {
+        self.rules.borrow().clone()
+    }
+
+    /// State of updates for every host, sorted by host.
+    pub fn status(&self) -> Vec<ServerStatus> {
+        let errors = self.errors.lock().unwrap();
+        self.hosts
+            .keys()
+            .map(|h| ServerStatus {
+                server: h.clone(),
+                cycle: self.metrics.cycle(h),
+                last_error: errors.get(h).cloned(),
+            })
+            .collect()
+    }
+
+    /// Returns true once an update has succeeded for at least one host, so that a single
+    /// server that can't be reached doesn't keep the exporter from ever being ready.
+    pub fn is_ready(&self) -> bool {
+        self.hosts.keys().any(|h| self.metrics.cycle(h).is_some())
+    }
+
+    /// Update metrics for `host`, waiting for any update already running for it to finish
+    /// first. Returns true if the update succeeded. Hosts this updater was not created with
+    /// are ignored.
+    pub async fn update(&self, host: &str) -> bool {
+        match self.hosts.get(host) {
+            Some(last) => {
+                let mut last = last.lock().await;
+                let res = self.run(host).await;
+                *last = Some(Instant::now());
+                res
+            }
+            None => false,
+        }
+    }
+
+    /// Update metrics for `host` every `refresh`, forever. Each update starts `refresh` after
+    /// the previous update for `host` finished, including updates run by `update_all`, so
+    /// refreshing metrics on demand restarts the schedule instead of being followed by
+    /// another update right away. Hosts this updater was not created with are ignored.
+    pub async fn update_every(&self, host: &str, refresh: Duration) {
+        let Some(last) = self.hosts.get(host) else {
+            return;
+        };
+
+        loop {
+            // Waits for an update that is already running to finish.
+            let next = last.lock().await.map(|t| t + refresh);
+            match next {
+                // Check again once the time is up in case another update ran meanwhile.
+                Some(t) if t > Instant::now() => tokio::time::sleep_until(t).await,
+                _ => {
+                    self.update(host).await;
+                }
+            }
+        }
+    }
+
+    /// Update metrics for every host where the last update finished more than `max_age`
+    /// ago, and wait for them to finish. Hosts with an update already running are not
+    /// updated again once it finishes, callers share the result of the running update.
+    ///
+    /// Each update runs as a separate task so that it completes even if the caller is
+    /// cancelled, e.g. by a client disconnecting.
+    pub async fn update_stale(self: &Arc<Self>, max_age: Duration) {
+        let mut tasks = Vec::with_capacity(self.hosts.len());
+        for host in self.hosts.keys() {
+            let (updater, host) = (self.clone(), host.clone());
+            tasks.push(tokio::spawn(async move {
+                let mut last = updater.hosts[&host].lock().await;
+                if last.map(|t| t.elapsed() < max_age).unwrap_or(false) {
+                    return;
+                }
+
+                updater.run(&host).await;
+                *last = Some(Instant::now());
+            }));
+        }
+
+        for t in tasks {
+            t.await.expect("update task panicked");
+        }
+    }
+
+    /// Update metrics for every host, regardless of when they were last updated, and wait
+    /// for them to finish. Returns whether the update succeeded for each host, sorted by
+    /// host. Like `update_stale`, updates run as separate tasks and complete even if the
+    /// caller is cancelled.
+    pub async fn update_all(self: &Arc<Self>) -> Vec<(String, bool)> {
+        let mut tasks = Vec::with_capacity(self.hosts.len());
+        for host in self.hosts.keys() {
+            let (updater, host) = (self.clone(), host.clone());
+            tasks.push(tokio::spawn(async move {
+                let res = updater.update(&host).await;
+                (host, res)
+            }));
+        }
+
+        let mut out = Vec::with_capacity(tasks.len());
+        for t in tasks {
+            out.push(t.await.expect("update task panicked"));
+        }
+
+        out
+    }
+
+    async fn run(&self, host: &str) -> bool {
+        let start = Instant::now();
+        // Grab the most recently loaded rules at the start of each update so that every
+        // key in a single update is handled by the same set of rules.
+        let cfg = self.rules.borrow().clone();
+        let mut retry = 0;
+        let aggregation = loop {
+            let reason = match self.attempt(host, &cfg).await {
+                Ok(a) => break a,
+                Err(reason) => reason,
+            };
+
+            if retry >= self.backoff.retries {
+                self.metrics.incr_failure(host, reason);
+                return false;
+            }
+
+            let delay = self.backoff.delay(retry);
+            retry += 1;
+            tracing::info!(message = "retrying update", host = %host, retry = retry, delay = ?delay);
+            self.metrics.incr_retry(host);
+            tokio::time::sleep(delay).await;
+        };
+
+        let time_taken = start.elapsed();
+        tracing::info!(
+            message = "updated metrics for memcached keys",
+            rule_group = cfg.name,
+            host = %host,
+            num_keys = aggregation.num_keys,
+            num_dropped_keys = aggregation.dropped.count,
+            sample_rate = aggregation.sample_rate,
+            num_unique_labels = aggregation.aggregates.len(),
+            series_dropped = aggregation.limits.series_dropped,
+            time_taken = ?time_taken,
+        );
+
+        // Rule series are identified by the position of each rule, so stats from rules
+        // that were replaced by a reload during this update would count different rules.
+        if Arc::ptr_eq(&cfg, &self.rules()) {
+            self.metrics.incr_rules(host, &cfg, &aggregation.rules);
+        }
+
+        if let Some(sample) = aggregation.unmatched.keys.as_ref().filter(|s| !s.keys.is_empty()) {
+            let keys: Vec<&str> = sample.keys.iter().map(|k| k.key.as_str()).collect();
+            tracing::debug!(message = "sample of keys not matched by any rule", host = %host, keys = ?keys);
+        }
+
+        // Replace the previous results for this server all at once so that scrapes never
+        // see a mix of series from this update and the one before it.
+        self.metrics.publish(
+            host,
+            Cycle {
+                aggregation,
+                completed: SystemTime::now(),
+                duration: time_taken,
+            },
+        );
+        self.metrics.incr_success(host, self.pool.workers(), time_taken);
+        true
+    }
+
+    /// Backoff used between attempts within an update.
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    /// Make a single attempt to fetch keys for `host`, giving up after the timeout.
+    async fn attempt(&self, host: &str, cfg: &Arc<RuleGroup>) -> Result<Aggregation, FailureReason> {
+        let res = match self.timeout {
+            Some(t) => tokio::time::timeout(t, crawl(host, &self.source, cfg, &self.pool)).await,
+            None => Ok(crawl(host, &self.source, cfg, &self.pool).await),
+        };
+
+        let (reason, message) = match res {
+            Ok(Ok(a)) => return Ok(a),
+            Ok(Err(e)) => {
+                tracing::warn!(message = "failed to fetch key metas", host = %host, err = %e);
+                (FailureReason::Error, e.to_string())
+            }
+            Err(_) => {
+                tracing::warn!(message = "timed out fetching key metas", host = %host, timeout = ?self.timeout);
+                (
+                    FailureReason::Timeout,
+                    format!("timed out after {:?}", self.timeout.unwrap_or_default()),
+                )
+            }
+        };
+
+        let error = UpdateError {
+            time: SystemTime::now(),
+            message,
+        };
+        self.errors.lock().unwrap().insert(host.to_owned(), error);
+        Err(reason)
+    }
+}
+
+/// Fetch all keys for a single server and run them through the rules, drops, and limits
+/// of `cfg`. Keys are aggregated as they are read so memory use depends on the number of
+/// unique label sets instead of the number of keys.
+pub async fn crawl(
+    host: &str,
+    source: &KeySource,
+    cfg: &Arc<RuleGroup>,
+    pool: &WorkerPool,
+) -> Result<Aggregation, MtopError> {
+    // The number of keys is only needed to pick a sample rate for a max_keys budget.
+    let num_keys = match &cfg.sampling {
+        Some(Sampling { max_keys: Some(_), .. }) => source.num_keys(host).await?,
+        _ => 0,
+    };
+
+    let sampler = Sampler::from_config(cfg.sampling.as_ref(), num_keys);
+
+    if pool.workers().is_parallel() {
+        let mut pipeline = ParallelPipeline::new(cfg.clone(), SystemTime::now(), pool).with_sampler(sampler);
+        source.metas(host, &mut pipeline).await?;
+        Ok(pipeline.finish().await)
+    } else {
+        let mut pipeline = Pipeline::new(cfg, SystemTime::now()).with_sampler(sampler);
+        source.metas(host, &mut |m: &Meta| pipeline.add(m)).await?;
+        Ok(pipeline.finish())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Updater;
+    use crate::backoff::Backoff;
+    use crate::config::RuleGroup;
+    use crate::crawler::CrawlerPool;
+    use crate::metrics::Metrics;
+    use crate::snapshot;
+    use crate::source::KeySource;
+    use mtop_client::{Meta, TLSConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    fn new_updater(name: &str) -> (Arc<Updater>, Arc<Metrics>) {
+        new_updater_hosts(name, &["cache-a:11211".to_owned()])
+    }
+
+    /// Create an updater for `hosts` reading from a snapshot that only has keys for
+    /// `cache-a:11211`.
+    fn new_updater_hosts(name: &str, hosts: &[String]) -> (Arc<Updater>, Arc<Metrics>) {
+        let path = std::env::temp_dir().join(format!("mkey-updater-{}-{}.zst", name, std::process::id()));
+        let mut w = snapshot::create(&path).unwrap();
+        snapshot::write_server(&mut w, "cache-a:11211").unwrap();
+        let meta = Meta {
+            key: "cart:user-1".to_owned(),
+            expires: -1,
+            size: 64,
+        };
+        snapshot::write_meta(&mut w, &meta).unwrap();
+        w.finish().unwrap();
+
+        let (_tx, rx) = watch::channel(Arc::new(RuleGroup::default()));
+        let metrics = Arc::new(Metrics::new());
+        let updater = Updater::new(
+            hosts,
+            rx,
+            Arc::new(KeySource::Snapshot(path)),
+            metrics.clone(),
+            Default::default(),
+        );
+
+        (Arc::new(updater), metrics)
+    }
+
+    fn num_updates(metrics: &Metrics) -> String {
+        find_value(
+            metrics,
+            "mkey_updates_total{server=\"cache-a:11211\",result=\"success\",reason=\"none\"}",
+        )
+    }
+
+    fn find_value(metrics: &Metrics, series: &str) -> String {
+        let mut buf = String::new();
+        metrics.encode(&mut buf).unwrap();
+        buf.lines()
+            .find(|l| l.starts_with(series))
+            .and_then(|l| l.rsplit(' ').next())
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_stale_fresh() {
+        let (updater, metrics) = new_updater("fresh");
+        assert!(!updater.is_ready());
+        assert_eq!("0", find_value(&metrics, "mkey_up{server=\"cache-a:11211\"}"));
+
+        updater.update_stale(Duration::from_secs(60)).await;
+        updater.update_stale(Duration::from_secs(60)).await;
+
+        assert_eq!("1", num_updates(&metrics));
+        assert_eq!(1, metrics.cycles().len());
+        assert!(updater.is_ready());
+        assert_eq!("1", find_value(&metrics, "mkey_up{server=\"cache-a:11211\"}"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_is_ready_any_host() {
+        let hosts = ["cache-a:11211".to_owned(), "cache-b:11211".to_owned()];
+        let (updater, _) = new_updater_hosts("ready", &hosts);
+
+        assert!(!updater.is_ready());
+        assert!(updater.update("cache-a:11211").await);
+        assert!(updater.is_ready());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_stale_expired() {
+        let (updater, metrics) = new_updater("expired");
+        updater.update_stale(Duration::ZERO).await;
+        updater.update_stale(Duration::ZERO).await;
+
+        assert_eq!("2", num_updates(&metrics));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_all() {
+        let (updater, metrics) = new_updater("all");
+        updater.update_stale(Duration::from_secs(60)).await;
+        let res = updater.update_all().await;
+
+        assert_eq!(vec![("cache-a:11211".to_owned(), true)], res);
+        assert_eq!("2", num_updates(&metrics));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_every_after_refresh() {
+        let (updater, metrics) = new_updater("every");
+        let refresh = Duration::from_millis(300);
+        updater.update("cache-a:11211").await;
+
+        let task = tokio::spawn({
+            let updater = updater.clone();
+            async move { updater.update_every("cache-a:11211", refresh).await }
+        });
+
+        // Refreshing halfway through the interval pushes the next scheduled update back
+        // instead of it running shortly after the refresh.
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        updater.update_all().await;
+        tokio::time::sleep(Duration::from_millis(225)).await;
+        assert_eq!("2", num_updates(&metrics));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!("3", num_updates(&metrics));
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_stale_concurrent() {
+        let (updater, metrics) = new_updater("concurrent");
+        let (a, b) = (updater.clone(), updater.clone());
+        tokio::join!(
+            a.update_stale(Duration::from_secs(60)),
+            b.update_stale(Duration::from_secs(60))
+        );
+
+        assert_eq!("1", num_updates(&metrics));
+    }
+
+    /// Start a server that accepts connections but never responds, like a server that
+    /// has stalled, and return its address.
+    async fn stalled_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        host
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_timeout() {
+        let host = stalled_server().await;
+        let pool = CrawlerPool::new(&TLSConfig::default()).await.unwrap();
+        let (_tx, rx) = watch::channel(Arc::new(RuleGroup::default()));
+        let metrics = Arc::new(Metrics::new());
+        let updater = Updater::new(
+            std::slice::from_ref(&host),
+            rx,
+            Arc::new(KeySource::Memcached(pool)),
+            metrics.clone(),
+            Default::default(),
+        )
+        .with_timeout(Duration::from_millis(50));
+
+        assert!(!updater.update(&host).await);
+        assert!(!updater.is_ready());
+
+        let series = format!(
+            "mkey_updates_total{{server=\"{}\",result=\"failure\",reason=\"timeout\"}}",
+            host
+        );
+        assert_eq!("1", find_value(&metrics, &series));
+        assert!(metrics.cycles().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update_retries() {
+        let host = stalled_server().await;
+        let pool = CrawlerPool::new(&TLSConfig::default()).await.unwrap();
+        let (_tx, rx) = watch::channel(Arc::new(RuleGroup::default()));
+        let metrics = Arc::new(Metrics::new());
+        let updater = Updater::new(
+            std::slice::from_ref(&host),
+            rx,
+            Arc::new(KeySource::Memcached(pool)),
+            metrics.clone(),
+            Default::default(),
+        )
+        .with_timeout(Duration::from_millis(20))
+        .with_backoff(Backoff {
+            retries: 2,
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(5),
+        });
+
+        updater.update(&host).await;
+
+        let failures = format!(
+            "mkey_updates_total{{server=\"{}\",result=\"failure\",reason=\"timeout\"}}",
+            host
+        );
+        let retries = format!("mkey_update_retries_total{{server=\"{}\"}}", host);
+        assert_eq!("1", find_value(&metrics, &failures));
+        assert_eq!("2", find_value(&metrics, &retries));
+    }
+}